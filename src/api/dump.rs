@@ -0,0 +1,283 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs::File as StdFile;
+use std::hash::Hash;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use chrono::DateTime;
+
+use crate::entities::{FileId, TagId, ValueId};
+use crate::errors::*;
+use crate::storage::{self, Storage, StorageOptions, Synchronous};
+
+/// Export the entire tag store - tags, values, files, file-tag associations and implications -
+/// into a portable, newline-delimited text stream. Each line is a tab-separated record prefixed
+/// with its type, so a dump also makes a readable snapshot of tag state that can be diffed.
+pub fn run_dump(db_path: &Path, dest_path: &Path) -> Result<()> {
+    let mut store = Storage::open(db_path)?;
+    let mut tx = store.begin_transaction()?;
+
+    let mut writer = BufWriter::new(StdFile::create(dest_path)?);
+
+    info!("Dumping tags");
+    for tag in storage::tag::tags(&mut tx)? {
+        writeln!(writer, "TAG\t{}\t{}", tag.id, escape(&tag.name))?;
+    }
+
+    info!("Dumping values");
+    for value in storage::value::values(&mut tx)? {
+        writeln!(writer, "VALUE\t{}\t{}", value.id, escape(&value.name))?;
+    }
+
+    info!("Dumping files");
+    for file in storage::file::files(&mut tx)? {
+        writeln!(
+            writer,
+            "FILE\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            file.id,
+            escape(&file.dir),
+            escape(&file.name),
+            escape(&file.fingerprint),
+            file.mod_time.to_rfc3339(),
+            file.size,
+            file.is_dir,
+        )?;
+    }
+
+    info!("Dumping file tags");
+    for file_tag in storage::filetag::file_tags(&mut tx)? {
+        writeln!(
+            writer,
+            "FILETAG\t{}\t{}\t{}",
+            file_tag.file_id,
+            file_tag.tag_id,
+            value_id_or_zero(&file_tag.value_id),
+        )?;
+    }
+
+    info!("Dumping implications");
+    for implication in storage::implication::implications(&mut tx)? {
+        writeln!(
+            writer,
+            "IMPLICATION\t{}\t{}\t{}\t{}",
+            implication.tag.id,
+            value_id_or_zero(&implication.value.as_ref().map(|v| v.id)),
+            implication.implied_tag.id,
+            value_id_or_zero(&implication.implied_value.as_ref().map(|v| v.id)),
+        )?;
+    }
+
+    tx.commit()
+}
+
+/// Reconstruct a database from a dump produced by `run_dump`. The dump's surrogate IDs are only
+/// meaningful within the database they came from, so every record is re-inserted here and its
+/// *new* ID is tracked in a lookup table, keeping foreign keys (file_tag -> tag/value/file,
+/// implication -> tag/value) consistent across the round trip.
+///
+/// `fast`, if set, opens with `Synchronous::Off`, so the load's single commit skips its fsyncs; an
+/// interrupted load can simply be re-run from the same dump file.
+pub fn run_load(db_path: &Path, src_path: &Path, fast: bool) -> Result<()> {
+    let synchronous = if fast { Synchronous::Off } else { Synchronous::default() };
+    let mut store = Storage::open_with_options(db_path, StorageOptions { synchronous })?;
+    let mut tx = store.begin_transaction()?;
+
+    let mut tag_ids: HashMap<TagId, TagId> = HashMap::new();
+    let mut value_ids: HashMap<ValueId, ValueId> = HashMap::new();
+    let mut file_ids: HashMap<FileId, FileId> = HashMap::new();
+
+    let reader = BufReader::new(StdFile::open(src_path)?);
+    for line in reader.lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split('\t').collect();
+        match fields[0] {
+            "TAG" => {
+                let old_id = TagId(fields[1].parse::<u32>()?);
+                let tag = storage::tag::insert_tag(&mut tx, &unescape(fields[2]))?;
+                tag_ids.insert(old_id, tag.id);
+            }
+            "VALUE" => {
+                let old_id = ValueId(fields[1].parse::<u32>()?);
+                let value = storage::value::insert_value(&mut tx, &unescape(fields[2]))?;
+                value_ids.insert(old_id, value.id);
+            }
+            "FILE" => {
+                let old_id = FileId(fields[1].parse::<u32>()?);
+                let dir = unescape(fields[2]);
+                let name = unescape(fields[3]);
+                let fingerprint = unescape(fields[4]);
+                let mod_time = DateTime::parse_from_rfc3339(fields[5])?;
+                let size: usize = fields[6].parse()?;
+                let is_dir: bool = fields[7].parse()?;
+
+                let file =
+                    storage::file::insert_file(&mut tx, &dir, &name, &fingerprint, mod_time, size, is_dir)?;
+                file_ids.insert(old_id, file.id);
+            }
+            "FILETAG" => {
+                let file_id = remap(&file_ids, fields[1], "file")?;
+                let tag_id = remap(&tag_ids, fields[2], "tag")?;
+                let value_id = remap_value(&value_ids, fields[3])?;
+
+                storage::filetag::add_file_tag(&mut tx, file_id, tag_id, value_id)?;
+            }
+            "IMPLICATION" => {
+                let tag_id = remap(&tag_ids, fields[1], "tag")?;
+                let value_id = remap_value(&value_ids, fields[2])?;
+                let implied_tag_id = remap(&tag_ids, fields[3], "tag")?;
+                let implied_value_id = remap_value(&value_ids, fields[4])?;
+
+                storage::implication::insert_implication(
+                    &mut tx,
+                    tag_id,
+                    value_id,
+                    implied_tag_id,
+                    implied_value_id,
+                )?;
+            }
+            other => return Err(format!("unrecognized dump record type '{}'", other).into()),
+        }
+    }
+
+    tx.commit()
+}
+
+fn value_id_or_zero(value_id: &Option<ValueId>) -> u32 {
+    value_id.map(|v| v.0).unwrap_or(0)
+}
+
+/// A surrogate ID, as tracked in `run_load`'s `*_ids` remapping tables: a newtype around `u32`
+/// that can be rebuilt from the raw dump field and printed back into an error message.
+trait SurrogateId: Copy + Eq + Hash + Display {
+    fn from_u32(id: u32) -> Self;
+}
+
+impl SurrogateId for TagId {
+    fn from_u32(id: u32) -> Self {
+        TagId(id)
+    }
+}
+
+impl SurrogateId for FileId {
+    fn from_u32(id: u32) -> Self {
+        FileId(id)
+    }
+}
+
+/// Look up the *new* ID a dump's surrogate `raw` ID was remapped to. `kind` is purely for the
+/// error message (e.g. "tag", "file").
+fn remap<K: SurrogateId>(ids: &HashMap<K, K>, raw: &str, kind: &str) -> Result<K> {
+    let old_id = K::from_u32(raw.parse::<u32>()?);
+    ids.get(&old_id)
+        .copied()
+        .ok_or_else(|| format!("dump references unknown {} id {}", kind, old_id).into())
+}
+
+fn remap_value(ids: &HashMap<ValueId, ValueId>, raw: &str) -> Result<Option<ValueId>> {
+    let old_id = ValueId(raw.parse::<u32>()?);
+    if old_id == ValueId(0) {
+        return Ok(None);
+    }
+
+    ids.get(&old_id)
+        .copied()
+        .map(Some)
+        .ok_or_else(|| format!("dump references unknown value id {}", old_id).into())
+}
+
+/// Escape backslashes, tabs and newlines so each record stays on a single, tab-delimited line.
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n")
+}
+
+fn unescape(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('t') => result.push('\t'),
+            Some('n') => result.push('\n'),
+            Some(other) => result.push(other),
+            None => {}
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn fresh_dir(name: &str) -> PathBuf {
+        let dir = Path::new("/tmp/tmsu-tests-dump").join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn dump_then_load_round_trips_a_tagged_file() {
+        let src_db_path = fresh_dir("src").join("db.sqlite");
+        let dst_db_path = fresh_dir("dst").join("db.sqlite");
+        let dump_path = fresh_dir("out").join("dump.txt");
+
+        Storage::create_at(&src_db_path).unwrap();
+        {
+            let mut store = Storage::open(&src_db_path).unwrap();
+            let mut tx = store.begin_transaction().unwrap();
+
+            let tag = storage::tag::insert_tag(&mut tx, "music").unwrap();
+            let value = storage::value::insert_value(&mut tx, "2020").unwrap();
+            let file = storage::file::insert_file(
+                &mut tx,
+                ".",
+                "song.mp3",
+                "deadbeef",
+                DateTime::parse_from_rfc3339("2020-01-01T00:00:00+00:00").unwrap(),
+                123,
+                false,
+            )
+            .unwrap();
+            storage::filetag::add_file_tag(&mut tx, file.id, tag.id, Some(value.id)).unwrap();
+
+            tx.commit().unwrap();
+        }
+
+        run_dump(&src_db_path, &dump_path).unwrap();
+
+        Storage::create_at(&dst_db_path).unwrap();
+        run_load(&dst_db_path, &dump_path, false).unwrap();
+
+        let mut store = Storage::open(&dst_db_path).unwrap();
+        let mut tx = store.begin_transaction().unwrap();
+
+        let files = storage::file::files(&mut tx).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].name, "song.mp3");
+
+        let file_tags = storage::filetag::file_tags(&mut tx).unwrap();
+        assert_eq!(file_tags.len(), 1);
+        assert_eq!(file_tags[0].file_id, files[0].id);
+
+        let tag = storage::tag::tag_by_id(&mut tx, &file_tags[0].tag_id).unwrap().unwrap();
+        assert_eq!(tag.name, "music");
+
+        let value_id = file_tags[0].value_id.expect("file tag should carry a value");
+        let value = storage::value::value_by_id(&mut tx, &value_id).unwrap().unwrap();
+        assert_eq!(value.name, "2020");
+    }
+}
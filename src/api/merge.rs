@@ -0,0 +1,73 @@
+use std::path::Path;
+
+use crate::api;
+use crate::entities::{Tag, Value};
+use crate::errors::*;
+use crate::storage::{self, Storage, Transaction};
+
+/// Merge each of `source_names` into `dest_name`: every file tagged with a source tag ends up
+/// tagged with the destination instead, any implications that referenced a source are rewritten
+/// to point at the destination, and the (now unused) source tag is deleted. A source that is
+/// already the destination is a no-op.
+pub fn run_merge_tag(db_path: &Path, source_names: &[&str], dest_name: &str) -> Result<()> {
+    let mut store = Storage::open(db_path)?;
+    let mut tx = store.begin_transaction()?;
+
+    let dest = api::load_existing_tag(&mut tx, dest_name)?;
+
+    for &source_name in source_names {
+        if source_name == dest_name {
+            continue;
+        }
+
+        let source = api::load_existing_tag(&mut tx, source_name)?;
+
+        info!("Merging tag '{}' into '{}'", source_name, dest_name);
+
+        merge_tag(&mut tx, &source, &dest).map_err(|e| {
+            format!("could not merge tag '{}' into '{}': {}", source_name, dest_name, e)
+        })?;
+    }
+
+    tx.commit()
+}
+
+/// See `run_merge_tag`; behaves the same way for values.
+pub fn run_merge_value(db_path: &Path, source_names: &[&str], dest_name: &str) -> Result<()> {
+    let mut store = Storage::open(db_path)?;
+    let mut tx = store.begin_transaction()?;
+
+    let dest = api::load_existing_value(&mut tx, dest_name)?;
+
+    for &source_name in source_names {
+        if source_name == dest_name {
+            continue;
+        }
+
+        let source = api::load_existing_value(&mut tx, source_name)?;
+
+        info!("Merging value '{}' into '{}'", source_name, dest_name);
+
+        merge_value(&mut tx, &source, &dest).map_err(|e| {
+            format!("could not merge value '{}' into '{}': {}", source_name, dest_name, e)
+        })?;
+    }
+
+    tx.commit()
+}
+
+fn merge_tag(tx: &mut Transaction, source: &Tag, dest: &Tag) -> Result<()> {
+    // `merge_file_tags` uses INSERT OR IGNORE, so files already carrying both the source and the
+    // destination tag don't end up with duplicate file_tag rows.
+    storage::filetag::merge_file_tags(tx, &source.id, &dest.id)?;
+    storage::filetag::delete_file_tags_by_tag_id(tx, &source.id)?;
+    storage::implication::retarget_tag_id(tx, &source.id, &dest.id)?;
+    storage::tag::delete_tag(tx, &source.id)
+}
+
+fn merge_value(tx: &mut Transaction, source: &Value, dest: &Value) -> Result<()> {
+    storage::filetag::merge_file_tags_by_value(tx, &source.id, &dest.id)?;
+    storage::filetag::delete_file_tags_by_value_id(tx, &source.id)?;
+    storage::implication::retarget_value_id(tx, &source.id, &dest.id)?;
+    storage::value::delete_value(tx, &source.id)
+}
@@ -0,0 +1,287 @@
+use std::path::Path;
+use std::process::Command;
+use std::rc::Rc;
+
+use crate::api;
+use crate::entities::path::{CanonicalPath, PathAuditor, ScopedPath};
+use crate::entities::{FileId, FileTag, Tag, Value};
+use crate::errors::*;
+use crate::storage::rule::{Action, Matcher, Rule, RuleId};
+use crate::storage::{self, Storage, Transaction};
+
+/// The outcome of applying one rule's action to one matched path.
+pub struct RuleOutcome {
+    pub path: String,
+    pub rule_id: RuleId,
+    pub change: ChangeSummary,
+}
+
+pub enum ChangeSummary {
+    /// A tag (optionally with a value) was - or, in dry-run mode, would be - applied. `implied`
+    /// lists any further tags that come along via implication.
+    Added {
+        tag_name: String,
+        value_name: Option<String>,
+        implied: Vec<String>,
+    },
+    Removed {
+        tag_name: String,
+        value_name: Option<String>,
+    },
+    Ran {
+        command: String,
+    },
+    Deleted,
+}
+
+/// Add a new rule, running its actions against every future `run_rules` call.
+pub fn run_add_rule(db_path: &Path, matcher: Matcher, actions: Vec<Action>) -> Result<Rule> {
+    let mut store = Storage::open(db_path)?;
+    let mut tx = store.begin_transaction()?;
+
+    info!("Adding rule for pattern '{}'", describe(&matcher));
+    let rule = storage::rule::insert_rule(&mut tx, &matcher, &actions)?;
+
+    tx.commit()?;
+    Ok(rule)
+}
+
+pub fn run_delete_rule(db_path: &Path, rule_id: RuleId) -> Result<()> {
+    let mut store = Storage::open(db_path)?;
+    let mut tx = store.begin_transaction()?;
+
+    info!("Deleting rule {}", rule_id.0);
+    storage::rule::delete_rule(&mut tx, &rule_id)?;
+
+    tx.commit()
+}
+
+/// Apply every stored rule, in order, to each of `paths`. In dry-run mode no writes are made
+/// (including no external commands being run) and the transaction is rolled back; the returned
+/// outcomes describe what would have happened instead.
+pub fn run_rules(db_path: &Path, paths: &[String], dry_run: bool) -> Result<Vec<RuleOutcome>> {
+    let mut store = Storage::open(db_path)?;
+    let root_path = store.root_path.clone();
+    let auditor = PathAuditor::new(root_path.clone());
+    let mut tx = store.begin_transaction()?;
+
+    let rules = storage::rule::rules(&mut tx)?;
+    let mut outcomes = Vec::new();
+
+    for path in paths {
+        for rule in &rules {
+            if !matches(&rule.matcher, path)? {
+                continue;
+            }
+
+            for action in &rule.actions {
+                outcomes.push(apply_action(&mut tx, &root_path, &auditor, rule.id, path, action, dry_run)?);
+            }
+        }
+    }
+
+    if dry_run {
+        // Dropping the transaction without committing rolls it back, so any lookups/inserts
+        // performed while building the preview above leave the database untouched.
+    } else {
+        tx.commit()?;
+    }
+
+    Ok(outcomes)
+}
+
+fn matches(matcher: &Matcher, path: &str) -> Result<bool> {
+    match matcher {
+        Matcher::Glob(pattern) => Ok(glob::Pattern::new(pattern)?.matches(path)),
+        Matcher::Regex(pattern) => Ok(regex::Regex::new(pattern)?.is_match(path)),
+    }
+}
+
+fn apply_action(
+    tx: &mut Transaction,
+    root_path: &Rc<CanonicalPath>,
+    auditor: &PathAuditor,
+    rule_id: RuleId,
+    path: &str,
+    action: &Action,
+    dry_run: bool,
+) -> Result<RuleOutcome> {
+    let change = match action {
+        Action::Add { tag_name, value_name } => {
+            apply_add(tx, root_path, auditor, path, tag_name, value_name, dry_run)?
+        }
+        Action::Rm { tag_name, value_name } => {
+            apply_rm(tx, root_path, auditor, path, tag_name, value_name, dry_run)?
+        }
+        Action::Run { command } => apply_run(command, path, dry_run)?,
+        Action::Del => apply_del(tx, root_path, auditor, path, dry_run)?,
+    };
+
+    Ok(RuleOutcome {
+        path: path.to_owned(),
+        rule_id,
+        change,
+    })
+}
+
+fn apply_add(
+    tx: &mut Transaction,
+    root_path: &Rc<CanonicalPath>,
+    auditor: &PathAuditor,
+    path: &str,
+    tag_name: &str,
+    value_name: &Option<String>,
+    dry_run: bool,
+) -> Result<ChangeSummary> {
+    if dry_run {
+        // Preview only: look up (but never create) the tag/value, and surface implied tags only
+        // when the tag already exists, since a not-yet-existing tag cannot imply anything yet.
+        let implied = match storage::tag::tag_by_name(tx, tag_name)? {
+            Some(tag) => {
+                implied_tag_names_for(tx, file_id_for_path(tx, root_path, auditor, path)?, &tag)?
+            }
+            None => Vec::new(),
+        };
+
+        return Ok(ChangeSummary::Added {
+            tag_name: tag_name.to_owned(),
+            value_name: value_name.clone(),
+            implied,
+        });
+    }
+
+    let file_id = file_id_for_path(tx, root_path, auditor, path)?;
+    let tag = load_or_insert_tag(tx, tag_name)?;
+    let value = match value_name {
+        Some(name) => Some(load_or_insert_value(tx, name)?),
+        None => None,
+    };
+
+    storage::filetag::add_file_tag(tx, file_id, tag.id, value.as_ref().map(|v| v.id))?;
+    let implied = implied_tag_names_for(tx, file_id, &tag)?;
+
+    Ok(ChangeSummary::Added {
+        tag_name: tag_name.to_owned(),
+        value_name: value_name.clone(),
+        implied,
+    })
+}
+
+fn apply_rm(
+    tx: &mut Transaction,
+    root_path: &Rc<CanonicalPath>,
+    auditor: &PathAuditor,
+    path: &str,
+    tag_name: &str,
+    value_name: &Option<String>,
+    dry_run: bool,
+) -> Result<ChangeSummary> {
+    if !dry_run {
+        let file_id = file_id_for_path(tx, root_path, auditor, path)?;
+        if let Some(tag) = storage::tag::tag_by_name(tx, tag_name)? {
+            let value_id = match value_name {
+                Some(name) => storage::value::value_by_name(tx, name)?.map(|v| v.id),
+                None => None,
+            };
+            storage::filetag::delete_file_tag(tx, file_id, tag.id, value_id)?;
+        }
+    }
+
+    Ok(ChangeSummary::Removed {
+        tag_name: tag_name.to_owned(),
+        value_name: value_name.clone(),
+    })
+}
+
+fn apply_run(command: &str, path: &str, dry_run: bool) -> Result<ChangeSummary> {
+    if !dry_run {
+        let expanded = command.replace("{}", path);
+        let status = Command::new("sh").arg("-c").arg(&expanded).status()?;
+        if !status.success() {
+            warn!("rule command '{}' exited with {}", expanded, status);
+        }
+    }
+
+    Ok(ChangeSummary::Ran {
+        command: command.to_owned(),
+    })
+}
+
+fn apply_del(
+    tx: &mut Transaction,
+    root_path: &Rc<CanonicalPath>,
+    auditor: &PathAuditor,
+    path: &str,
+    dry_run: bool,
+) -> Result<ChangeSummary> {
+    if !dry_run {
+        let file_id = file_id_for_path(tx, root_path, auditor, path)?;
+        storage::filetag::delete_file_tags_by_file_id(tx, file_id)?;
+        storage::file::delete_untagged_files(tx, &[file_id])?;
+    }
+
+    Ok(ChangeSummary::Deleted)
+}
+
+fn file_id_for_path(
+    tx: &mut Transaction,
+    root_path: &Rc<CanonicalPath>,
+    auditor: &PathAuditor,
+    path: &str,
+) -> Result<FileId> {
+    let scoped_path = ScopedPath::new(root_path.clone(), Path::new(path))?;
+    auditor.audit(&scoped_path)?;
+
+    match storage::file::file_by_path(tx, &scoped_path)? {
+        Some(file) => Ok(file.id),
+        None => Err(format!("'{}' is not a tracked file; tag it before applying rules", path).into()),
+    }
+}
+
+fn implied_tag_names_for(tx: &mut Transaction, file_id: FileId, tag: &Tag) -> Result<Vec<String>> {
+    let mut file_tags = storage::filetag::file_tags_by_tag_id(tx, &tag.id)?;
+    // In dry-run mode `file_id` doesn't carry `tag` yet: expand as if it already did (without a
+    // value), so the preview still lists what the tag implies.
+    if !file_tags.iter().any(|ft| ft.file_id == file_id) {
+        file_tags.push(FileTag {
+            file_id,
+            tag_id: tag.id,
+            value_id: None,
+            explicit: true,
+            implicit: false,
+        });
+    }
+    let expanded = api::tags::add_implied_file_tags(tx, file_tags)?;
+
+    let mut names = Vec::new();
+    for file_tag in expanded {
+        if file_tag.implicit && file_tag.file_id == file_id {
+            if let Some(implied_tag) = storage::tag::tag_by_id(tx, &file_tag.tag_id)? {
+                names.push(implied_tag.name);
+            }
+        }
+    }
+
+    Ok(names)
+}
+
+fn load_or_insert_tag(tx: &mut Transaction, name: &str) -> Result<Tag> {
+    match storage::tag::tag_by_name(tx, name)? {
+        Some(tag) => Ok(tag),
+        None => storage::tag::insert_tag(tx, name),
+    }
+}
+
+fn load_or_insert_value(tx: &mut Transaction, name: &str) -> Result<Value> {
+    match storage::value::value_by_name(tx, name)? {
+        Some(value) => Ok(value),
+        None => storage::value::insert_value(tx, name),
+    }
+}
+
+fn describe(matcher: &Matcher) -> &str {
+    match matcher {
+        Matcher::Glob(pattern) => pattern,
+        Matcher::Regex(pattern) => pattern,
+    }
+}
@@ -0,0 +1,154 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::entities::path::ScopedPath;
+use crate::entities::File;
+use crate::errors::*;
+use crate::storage::{self, Storage};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileStatus {
+    /// Tracked, present on disk, unchanged, and still carrying at least one tag.
+    Tagged,
+    /// Tracked, present and unchanged, but has no tags left (a candidate for `repair --remove`).
+    Untagged,
+    /// Tracked, but its mod_time/size no longer match what's stored (the fingerprint is not
+    /// recomputed).
+    Modified,
+    /// Tracked, but the path no longer resolves to anything.
+    Missing,
+}
+
+pub struct StatusEntry {
+    pub path: PathBuf,
+    pub status: FileStatus,
+}
+
+/// One group of paths sharing the same status, mirroring how `api::tags::FileTagGroup` groups
+/// tags per file.
+pub struct StatusGroup {
+    pub status: FileStatus,
+    pub paths: Vec<PathBuf>,
+}
+
+/// Classify every tracked file under `paths` (or the whole store, if `paths` is empty) by
+/// re-stat-ing it and comparing against the stored record. `follow_symlinks` is honored the same
+/// way as in `list_tags_for_paths`.
+pub fn run_status(db_path: &Path, paths: &[PathBuf], follow_symlinks: bool) -> Result<Vec<StatusGroup>> {
+    let entries = status_entries(db_path, paths, follow_symlinks)?;
+    Ok(group_by_status(entries))
+}
+
+fn group_by_status(entries: Vec<StatusEntry>) -> Vec<StatusGroup> {
+    const ORDER: [FileStatus; 4] = [
+        FileStatus::Missing,
+        FileStatus::Modified,
+        FileStatus::Untagged,
+        FileStatus::Tagged,
+    ];
+
+    ORDER
+        .iter()
+        .filter_map(|&status| {
+            let paths: Vec<_> = entries
+                .iter()
+                .filter(|e| e.status == status)
+                .map(|e| e.path.clone())
+                .collect();
+
+            if paths.is_empty() {
+                None
+            } else {
+                Some(StatusGroup { status, paths })
+            }
+        })
+        .collect()
+}
+
+fn status_entries(db_path: &Path, paths: &[PathBuf], follow_symlinks: bool) -> Result<Vec<StatusEntry>> {
+    let mut store = Storage::open(db_path)?;
+    let root_path = store.root_path.clone();
+    let mut tx = store.begin_transaction()?;
+
+    let mut entries = Vec::new();
+
+    let files = if paths.is_empty() {
+        storage::file::files(&mut tx)?
+    } else {
+        let mut files = Vec::with_capacity(paths.len());
+        for path in paths {
+            let resolved = if follow_symlinks {
+                match path.canonicalize() {
+                    Ok(resolved) => resolved,
+                    // The path itself no longer resolves - this is exactly a Missing file, not a
+                    // hard error, so report it as such using the path the caller passed in (there's
+                    // no File record to recover a "real" path from, since we never got to look one up).
+                    Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                        entries.push(StatusEntry {
+                            path: path.clone(),
+                            status: FileStatus::Missing,
+                        });
+                        continue;
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            } else {
+                path.to_path_buf()
+            };
+
+            let scoped_path = ScopedPath::new(root_path.clone(), &resolved)?;
+            if let Some(file) = storage::file::file_by_path(&mut tx, &scoped_path)? {
+                files.push(file);
+            }
+        }
+        files
+    };
+
+    entries.reserve(files.len());
+    for file in files {
+        let path = Path::new(&file.dir).join(&file.name);
+
+        let status = match current_metadata_matches(&file, &path, follow_symlinks)? {
+            None => FileStatus::Missing,
+            Some(false) => FileStatus::Modified,
+            Some(true) => {
+                let file_tags = storage::filetag::file_tags_by_file_id(&mut tx, &file.id)?;
+                if file_tags.is_empty() {
+                    FileStatus::Untagged
+                } else {
+                    FileStatus::Tagged
+                }
+            }
+        };
+
+        entries.push(StatusEntry { path, status });
+    }
+
+    tx.commit()?;
+
+    Ok(entries)
+}
+
+/// Returns `None` if `path` no longer resolves, `Some(true)` if its size and mod_time still match
+/// the stored record, `Some(false)` otherwise.
+fn current_metadata_matches(file: &File, path: &Path, follow_symlinks: bool) -> Result<Option<bool>> {
+    let metadata = if follow_symlinks {
+        fs::metadata(path)
+    } else {
+        fs::symlink_metadata(path)
+    };
+
+    let metadata = match metadata {
+        Ok(metadata) => metadata,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let stored_mod_time: SystemTime = file.mod_time.into();
+
+    Ok(Some(
+        metadata.len() as usize == file.size && metadata.modified()? == stored_mod_time,
+    ))
+}
@@ -1,10 +1,15 @@
 use std::collections::HashSet;
+use std::fs;
+#[cfg(unix)]
+use std::os::unix::fs::MetadataExt;
+#[cfg(windows)]
+use std::os::windows::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 
 use crate::api;
 use crate::entities::{self, path::ScopedPath, FileId, TagId, ValueId};
 use crate::errors::*;
-use crate::storage::{self, Storage, Transaction};
+use crate::storage::{self, FileTagStore, Storage, TagStore, Transaction};
 
 /// One group of tags. If the value name is present, then the tags correspond to it
 pub struct ValueTagGroup {
@@ -14,6 +19,9 @@ pub struct ValueTagGroup {
 
 pub struct FileTagGroup {
     pub path: PathBuf,
+    /// Whether `path` names a directory, taken from `ScopedPath::metadata()` once the file has
+    /// been found - so callers displaying it don't need their own `path.is_dir()`.
+    pub is_dir: bool,
     pub tags: Vec<TagData>,
 }
 
@@ -74,13 +82,18 @@ pub fn list_tags_for_values(db_path: &Path, value_names: &[&str]) -> Result<Vec<
     }
 }
 
-fn tag_names_by_value_id(tx: &mut Transaction, value_id: &ValueId) -> Result<Vec<String>> {
-    let file_tags = storage::filetag::file_tags_by_value_id(tx, value_id)?;
+/// Generic over `TagStore`/`FileTagStore` (rather than hard-coded to `Transaction`) so this can be
+/// exercised against `storage::mem::MemCatalog` in tests, without a real sqlite database.
+fn tag_names_by_value_id<S: TagStore + FileTagStore>(
+    store: &mut S,
+    value_id: &ValueId,
+) -> Result<Vec<String>> {
+    let file_tags = store.file_tags_by_value_id(value_id)?;
 
     let mut tag_names = HashSet::new();
 
     for file_tag in file_tags {
-        let tag_opt = storage::tag::tag_by_id(tx, &file_tag.tag_id)?;
+        let tag_opt = store.tag_by_id(&file_tag.tag_id)?;
 
         match tag_opt {
             Some(tag) => tag_names.insert(tag.name),
@@ -104,6 +117,10 @@ pub fn list_tags_for_paths(
     let mut tx = store.begin_transaction()?;
 
     let mut tag_groups = Vec::with_capacity(paths.len());
+    // With `follow_symlinks`, a symlink and its target are the same identity, so e.g. `a.mp3` and
+    // `symlink-to-a.mp3` collapse into one group. Without it, `file_identity` is based on the
+    // symlink itself rather than what it points at, so the symlink keeps its own identity.
+    let mut seen = HashSet::new();
 
     for path in paths {
         info!("Resolving path '{}'", path.display());
@@ -113,6 +130,13 @@ pub fn list_tags_for_paths(
             path.to_path_buf()
         };
 
+        if let Some(identity) = file_identity(&path, follow_symlinks) {
+            if !seen.insert(identity) {
+                info!("'{}' resolves to an already-listed file, skipping", path.display());
+                continue;
+            }
+        }
+
         info!("Looking up file '{}'", path.display());
         let scoped_path = ScopedPath::new(&root_path, &path)?;
         let file_opt = storage::file::file_by_path(&mut tx, &scoped_path)?;
@@ -120,8 +144,9 @@ pub fn list_tags_for_paths(
         info!("Retrieving tags");
         if let Some(file) = file_opt {
             let tags = tag_data_by_file_id(&mut tx, &file.id)?;
+            let is_dir = scoped_path.metadata().map(|m| m.is_dir()).unwrap_or(false);
 
-            tag_groups.push(FileTagGroup { path, tags });
+            tag_groups.push(FileTagGroup { path, is_dir, tags });
         }
     }
 
@@ -130,6 +155,36 @@ pub fn list_tags_for_paths(
     Ok(tag_groups)
 }
 
+/// A platform-specific key identifying the underlying file `path` resolves to, used to collapse
+/// several argument paths that refer to the same file into a single `FileTagGroup`. `None` if
+/// `path` can't be stat'ed (e.g. it doesn't exist) or if this platform has no cheap way to get a
+/// stable file identity, in which case paths are simply never collapsed.
+///
+/// When `follow_symlinks` is set, this stats through symlinks, so a symlink and its target share
+/// an identity; otherwise it stats the symlink itself, so it keeps an identity of its own.
+fn file_identity(path: &Path, follow_symlinks: bool) -> Option<(u64, u64)> {
+    let metadata = if follow_symlinks {
+        fs::metadata(path)
+    } else {
+        fs::symlink_metadata(path)
+    };
+    let metadata = metadata.ok()?;
+
+    #[cfg(unix)]
+    {
+        Some((metadata.dev(), metadata.ino()))
+    }
+    #[cfg(windows)]
+    {
+        // Themselves `None` when the filesystem doesn't support them (e.g. some network shares).
+        Some((metadata.volume_serial_number()? as u64, metadata.file_index()?))
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        None
+    }
+}
+
 fn tag_data_by_file_id(tx: &mut Transaction, file_id: &FileId) -> Result<Vec<TagData>> {
     // Get explicit file tags
     let mut file_tags = storage::filetag::file_tags_by_file_id(tx, file_id)?;
@@ -170,8 +225,9 @@ fn tag_data_by_file_id(tx: &mut Transaction, file_id: &FileId) -> Result<Vec<Tag
     Ok(tag_data)
 }
 
-// TODO: move to a more central place, if this ends up being reused in other subcommands
-fn add_implied_file_tags(
+/// Expand `file_tags` with every tag/value pair they imply (see the `imply` subcommand), marking
+/// the added entries as implicit. Also used by the rule engine to preview implied tags.
+pub(crate) fn add_implied_file_tags(
     tx: &mut Transaction,
     file_tags: Vec<entities::FileTag>,
 ) -> Result<Vec<entities::FileTag>> {
@@ -220,3 +276,38 @@ fn find_file_tag_for_pair<'a>(
         .iter_mut()
         .find(|ft| ft.tag_id == *tag_id && ft.value_id == value_id.as_ref().map(|v| v.id))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::mem::MemCatalog;
+    use crate::storage::ValueStore;
+
+    #[test]
+    fn tag_names_by_value_id_returns_sorted_distinct_names() {
+        let mut store = MemCatalog::new();
+        let value = store.insert_value("2020").unwrap();
+        let other_value = store.insert_value("2021").unwrap();
+        let zebra = store.insert_tag("zebra").unwrap();
+        let apple = store.insert_tag("apple").unwrap();
+
+        store.add_file_tag(FileId(1), zebra.id, Some(value.id)).unwrap();
+        store.add_file_tag(FileId(2), apple.id, Some(value.id)).unwrap();
+        // Same tag/value pair on a second file shouldn't produce a duplicate name.
+        store.add_file_tag(FileId(3), apple.id, Some(value.id)).unwrap();
+        store.add_file_tag(FileId(4), apple.id, Some(other_value.id)).unwrap();
+
+        let names = tag_names_by_value_id(&mut store, &value.id).unwrap();
+
+        assert_eq!(names, vec!["apple".to_owned(), "zebra".to_owned()]);
+    }
+
+    #[test]
+    fn tag_names_by_value_id_errors_on_dangling_tag_id() {
+        let mut store = MemCatalog::new();
+        let value = store.insert_value("2020").unwrap();
+        store.add_file_tag(FileId(1), TagId(99), Some(value.id)).unwrap();
+
+        assert!(tag_names_by_value_id(&mut store, &value.id).is_err());
+    }
+}
@@ -0,0 +1,29 @@
+use std::path::PathBuf;
+
+use structopt::StructOpt;
+
+use crate::api;
+use crate::cli::{locate_db, GlobalOptions};
+use crate::errors::*;
+
+/// Reconstructs the tag database from a dump file produced by the dump subcommand.
+#[derive(Debug, StructOpt)]
+pub struct LoadOptions {
+    /// Trade durability for throughput by skipping fsync on every write (sqlite
+    /// synchronous=OFF): a crash or power loss during the load can corrupt the database, but the
+    /// load can simply be re-run from the same dump file
+    #[structopt(long, alias("no-sync"))]
+    fast: bool,
+
+    /// The dump file to load
+    src_path: PathBuf,
+}
+
+impl LoadOptions {
+    pub fn execute(&self, global_opts: &GlobalOptions) -> Result<()> {
+        let db_path = locate_db(&global_opts.database)?;
+        info!("Database path: {}", db_path.display());
+
+        api::dump::run_load(&db_path, &self.src_path, self.fast)
+    }
+}
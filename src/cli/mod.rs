@@ -0,0 +1,40 @@
+use std::path::{MAIN_SEPARATOR, PathBuf};
+
+use clap::arg_enum;
+use structopt::StructOpt;
+
+pub mod load;
+pub mod tags;
+
+arg_enum! {
+    #[derive(Debug, PartialEq)]
+    pub enum ColorMode {
+        Auto,
+        Always,
+        Never,
+    }
+}
+
+/// Options shared by every subcommand.
+#[derive(Debug, StructOpt)]
+pub struct GlobalOptions {
+    /// The path to the database to use
+    #[structopt(long, global(true))]
+    pub database: Option<PathBuf>,
+
+    /// Use colored output: auto, always, never
+    #[structopt(long, global(true), default_value("auto"))]
+    pub color: ColorMode,
+
+    /// The path separator to append to directory entries (defaults to the OS separator)
+    #[structopt(long("path-separator"), global(true))]
+    path_separator: Option<char>,
+}
+
+impl GlobalOptions {
+    /// The separator to use when rendering directory paths: the one the user supplied, or the OS
+    /// default otherwise.
+    pub fn actual_path_separator(&self) -> char {
+        self.path_separator.unwrap_or(MAIN_SEPARATOR)
+    }
+}
@@ -97,6 +97,7 @@ impl TagsOptions {
                 self.show_count,
                 self.one_per_line,
                 use_colors,
+                global_opts.actual_path_separator(),
             );
         } else {
             let tag_groups = api::tags::list_all_tags(&db_path)?;
@@ -196,6 +197,7 @@ fn print_file_tag_groups(
     show_count: bool,
     one_per_line: bool,
     use_colors: bool,
+    path_separator: char,
 ) {
     if groups.is_empty() {
         return;
@@ -210,32 +212,34 @@ fn print_file_tag_groups(
 
     match groups.len() {
         1 => {
-            let path_opt = match print_path {
-                true => Some(&groups[0].path),
+            let group_opt = match print_path {
+                true => Some(&groups[0]),
                 false => None,
             };
 
             print_file_tag_group(
-                path_opt,
+                group_opt,
                 &groups[0].tags,
                 show_count,
                 one_per_line,
                 use_colors,
+                path_separator,
             );
         }
         _ => {
             for tag_group in groups {
-                let path_opt = match print_path {
-                    true => Some(&tag_group.path),
+                let group_opt = match print_path {
+                    true => Some(tag_group),
                     false => None,
                 };
 
                 print_file_tag_group(
-                    path_opt,
+                    group_opt,
                     &tag_group.tags,
                     show_count,
                     one_per_line,
                     use_colors,
+                    path_separator,
                 );
                 if !show_count && one_per_line {
                     println!();
@@ -246,20 +250,21 @@ fn print_file_tag_groups(
 }
 
 fn print_file_tag_group(
-    file_path: Option<&PathBuf>,
+    file_group: Option<&api::tags::FileTagGroup>,
     tags: &[api::tags::TagData],
     show_count: bool,
     one_per_line: bool,
     use_colors: bool,
+    path_separator: char,
 ) {
     if show_count {
-        match file_path {
-            Some(path) => println!("{}: {}", path.display(), tags.len()),
+        match file_group {
+            Some(group) => println!("{}: {}", format_file_path(group, path_separator), tags.len()),
             None => println!("{}", tags.len()),
         }
     } else if one_per_line {
-        if let Some(path) = file_path {
-            println!("{}", path.display());
+        if let Some(group) = file_group {
+            println!("{}", format_file_path(group, path_separator));
         }
         for tag_data in tags {
             println!("{}", format_tag_data(&tag_data, use_colors));
@@ -269,13 +274,27 @@ fn print_file_tag_group(
             .iter()
             .map(|td| format_tag_data(td, use_colors))
             .collect();
-        match file_path {
-            Some(path) => println!("{}: {}", path.display(), formatted.join(" ")),
+        match file_group {
+            Some(group) => println!("{}: {}", format_file_path(group, path_separator), formatted.join(" ")),
             None => print_columns(&formatted),
         };
     }
 }
 
+/// Render `group`'s path for display, appending `path_separator` when it names a directory - so
+/// tagged directories stand out from tagged files the same way `fd`'s listings do. Purely
+/// cosmetic: the database always stores directories without a trailing separator. Uses
+/// `FileTagGroup::is_dir`, which was already derived from `ScopedPath::metadata()` while looking
+/// the file up, rather than re-stat-ing here.
+fn format_file_path(group: &api::tags::FileTagGroup, path_separator: char) -> String {
+    let displayed = group.path.display().to_string();
+    if group.is_dir && !displayed.ends_with(path_separator) {
+        format!("{}{}", displayed, path_separator)
+    } else {
+        displayed
+    }
+}
+
 fn format_tag_data(tag_data: &api::tags::TagData, use_colors: bool) -> String {
     let style = if use_colors {
         match (tag_data.explicit, tag_data.implicit) {
@@ -1,9 +1,15 @@
-use std::ffi::OsString;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fmt;
 use std::fs;
 use std::ops;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::rc::Rc;
 
+use once_cell::unsync::OnceCell;
+use users::get_user_by_name;
+
 use crate::errors::*;
 
 /// Simple wrapper around PathBuf to enforce stronger typing.
@@ -82,31 +88,163 @@ impl ops::Deref for AbsPath {
     }
 }
 
+/// Lexically clean a path, without touching the filesystem: resolve `.`/`..` components and drop
+/// redundant separators. Unlike the previous implementation (which delegated to the `path_clean`
+/// crate and operated on `&str`), this works directly on `Path::components()`, so it handles
+/// Windows prefixes/separators and paths that aren't valid UTF-8.
 fn clean(p: PathBuf) -> PathBuf {
-    // FIXME TODO: do not rely on path_clean, because:
-    // 1. It doesn't support Windows properly
-    // 2. It works on strings, but not on paths
-    // We could do something similar to https://doc.rust-lang.org/std/path/struct.Path.html#method.components
-    let s =
-        path_clean::clean(p.to_str().unwrap_or_else(|| {
-            panic!("Bug: path cannot be converted to a string: {}", p.display())
-        }));
-    PathBuf::from(s)
+    let mut stack: Vec<Component> = Vec::new();
+    let mut rooted = false;
+
+    for component in p.components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir => {
+                rooted = true;
+                stack.push(component);
+            }
+            Component::CurDir => (),
+            Component::ParentDir => match stack.last() {
+                Some(Component::Normal(_)) => {
+                    stack.pop();
+                }
+                // A ".." right after the root has nowhere higher to go, so it is discarded.
+                _ if rooted => (),
+                _ => stack.push(component),
+            },
+            Component::Normal(_) => stack.push(component),
+        }
+    }
+
+    if stack.is_empty() {
+        return if p.is_relative() {
+            PathBuf::from(".")
+        } else {
+            PathBuf::new()
+        };
+    }
+
+    stack.iter().collect()
+}
+
+/// Shared cache of `fs::metadata`/`fs::symlink_metadata` results, keyed by path. `ScopedPath::new`
+/// stats (at least) every path prefix it descends through; for a batch operation constructing many
+/// `ScopedPath`s under the same directory tree, sharing one `MetadataCache` across the whole batch
+/// means each concrete path is stat'd (and lstat'd) at most once overall, rather than once per
+/// `ScopedPath`. `ScopedPath::new` uses a fresh, call-local cache, so the saving there is limited to
+/// avoiding re-stating the same component twice within a single construction; pass a cache created
+/// up-front and shared across calls (via `ScopedPath::new_with_cache`) to get the cross-call saving.
+#[derive(Clone, Default)]
+pub struct MetadataCache {
+    lstat: Rc<RefCell<HashMap<PathBuf, Option<fs::Metadata>>>>,
+    stat: Rc<RefCell<HashMap<PathBuf, Option<fs::Metadata>>>>,
+}
+
+impl MetadataCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cached `fs::symlink_metadata` (does not follow a final symlink).
+    fn lstat(&self, path: &Path) -> Option<fs::Metadata> {
+        if let Some(cached) = self.lstat.borrow().get(path) {
+            return cached.clone();
+        }
+
+        let metadata = fs::symlink_metadata(path).ok();
+        self.lstat.borrow_mut().insert(path.to_path_buf(), metadata.clone());
+        metadata
+    }
+
+    /// Cached `fs::metadata` (follows symlinks, like `Path::exists`/`Path::is_dir`).
+    fn stat(&self, path: &Path) -> Option<fs::Metadata> {
+        if let Some(cached) = self.stat.borrow().get(path) {
+            return cached.clone();
+        }
+
+        let metadata = fs::metadata(path).ok();
+        self.stat.borrow_mut().insert(path.to_path_buf(), metadata.clone());
+        metadata
+    }
 }
 
-fn canonicalize_or_clean(path: PathBuf) -> Result<PathBuf> {
-    if path.exists() {
+fn canonicalize_or_clean(path: PathBuf, cache: &MetadataCache) -> Result<PathBuf> {
+    if cache.stat(&path).is_some() {
         Ok(path.canonicalize()?)
     } else {
         Ok(clean(path))
     }
 }
 
-fn is_symlink(path: &Path) -> bool {
-    if let Ok(metadata) = fs::symlink_metadata(path) {
-        return metadata.file_type().is_symlink();
+fn is_symlink(path: &Path, cache: &MetadataCache) -> bool {
+    cache
+        .lstat(path)
+        .map(|metadata| metadata.file_type().is_symlink())
+        .unwrap_or(false)
+}
+
+/// Is `name` an "ndots" component, i.e. a run of three or more dots (`...`, `....`, etc.), which
+/// shells such as zsh and fish expand into a chain of `..`?
+fn is_ndots(name: &OsStr) -> bool {
+    match name.to_str() {
+        Some(s) => s.len() >= 3 && s.bytes().all(|b| b == b'.'),
+        None => false,
+    }
+}
+
+/// Resolve `~` (or `~user`) to the relevant home directory, or `None` if the user doesn't exist
+/// (or, for a bare `~`, if `$HOME` isn't set).
+fn expand_home(user: &str) -> Option<PathBuf> {
+    if user.is_empty() {
+        std::env::var_os("HOME").map(PathBuf::from)
+    } else {
+        get_user_by_name(user).map(|u| u.home_dir().to_path_buf())
+    }
+}
+
+/// Does the literal path `base/prefix/name` already exist on disk? Used to decide whether a
+/// leading `~...` or an ndots component should be expanded, or left alone because it refers to an
+/// actual file or directory with that (unusual) name.
+fn component_exists(base: &CanonicalPath, prefix: &Path, name: &OsStr) -> bool {
+    if prefix.is_absolute() {
+        prefix.join(name).exists()
+    } else {
+        base.join(prefix).join(name).exists()
+    }
+}
+
+/// Expand a leading `~` or `~user` to the relevant home directory, and any "ndots" component
+/// (`...`, `....`, ...) to the equivalent run of `..` components. Both kinds of expansion are
+/// skipped when the literal component actually exists on disk, so a real `~` or `...` directory is
+/// never clobbered. A `~`/`~user` expansion is always absolute, regardless of whether `path` was
+/// given as relative or absolute.
+fn expand_tilde_and_ndots(base: &CanonicalPath, path: &Path) -> PathBuf {
+    let mut components = path.components();
+    let mut expanded = PathBuf::new();
+
+    if let Some(Component::Normal(first)) = components.clone().next() {
+        if let Some(s) = first.to_str() {
+            if s.starts_with('~') && !component_exists(base, &expanded, first) {
+                if let Some(home) = expand_home(&s[1..]) {
+                    expanded.push(home);
+                    components.next();
+                }
+            }
+        }
+    }
+
+    for component in components {
+        match component {
+            Component::Normal(name) if is_ndots(name) && !component_exists(base, &expanded, name) => {
+                // "..." -> "../..", "...." -> "../../..", etc.
+                for _ in 0..name.len() - 1 {
+                    expanded.push("..");
+                }
+            }
+            other => expanded.push(other.as_os_str()),
+        }
     }
-    false
+
+    expanded
 }
 
 /// From a logical perspective, a `ScopedPath` holds an absolute path. However, it does not
@@ -122,6 +260,16 @@ pub struct ScopedPath {
     base: Rc<CanonicalPath>,
     inner: PathBuf,
     absolute: PathBuf,
+    // Whether the path the caller passed in (after tilde/ndots expansion) was relative to `base`,
+    // as opposed to a path the caller spelled out as absolute on purpose. `inner`/`absolute` alone
+    // can't answer this once construction has fully canonicalized an existing path: a relative
+    // input that resolves outside `base` and an absolute input pointing at the very same place end
+    // up looking identical. `PathAuditor::audit` needs the distinction to reject the former.
+    relative_input: bool,
+    // Lazily populated, and never shared with the `MetadataCache` used during construction: by the
+    // time a caller asks for this, `absolute` is fixed, so a single `OnceCell` is all this path
+    // itself ever needs.
+    metadata: OnceCell<Option<fs::Metadata>>,
 }
 
 impl ScopedPath {
@@ -132,6 +280,11 @@ impl ScopedPath {
     /// The given `path` can be either relative or absolute. If relative, it is assumed to be
     /// relative to `base`, not to the current directory.
     ///
+    /// Before anything else, a leading `~` or `~user` is expanded to the relevant home directory
+    /// (becoming absolute in the process), and any "ndots" component (`...`, `....`, ...) is
+    /// expanded to the equivalent run of `..` components - unless the literal component exists on
+    /// disk, in which case it is left untouched. See `expand_tilde_and_ndots`.
+    ///
     /// E.g.:
     /// ```rust
     /// let base = Rc::new(CanonicalPath::new("/foo/bar").unwrap());
@@ -142,9 +295,24 @@ impl ScopedPath {
     /// assert_eq!(ScopedPath::new(base.clone(), "./baz/.././dummy/../").unwrap().inner, &Path::new("."));
     /// ```
     pub fn new<P: AsRef<Path>>(base: Rc<CanonicalPath>, path: P) -> Result<Self> {
+        // A fresh, call-local cache only saves a stat when the same prefix is reached twice
+        // within this one construction (e.g. `a/../a`); see `new_with_cache` for sharing it
+        // across a whole batch of paths.
+        Self::new_with_cache(base, path, &MetadataCache::new())
+    }
+
+    /// Like `new`, but shares `cache` with other `ScopedPath` constructions, so that a batch
+    /// operation walking many paths under the same directory tree (e.g. tagging a whole
+    /// directory) stats each concrete path at most once across the entire batch.
+    pub fn new_with_cache<P: AsRef<Path>>(
+        base: Rc<CanonicalPath>,
+        path: P,
+        cache: &MetadataCache,
+    ) -> Result<Self> {
         assert!(base.is_dir(), "The base must be a directory");
 
-        let path = path.as_ref().to_path_buf();
+        let path = expand_tilde_and_ndots(&base, path.as_ref());
+        let relative_input = path.is_relative();
 
         let mut growing = if path.is_relative() {
             base.to_path_buf()
@@ -158,7 +326,7 @@ impl ScopedPath {
         let mut components = path.components();
         while let Some(part) = components.next() {
             let extended = growing.join(part);
-            if extended.starts_with(&*base) && is_symlink(&extended) {
+            if extended.starts_with(&*base) && is_symlink(&extended, cache) {
                 // At this point we know that "growing" is canonical (or clean), since every
                 // iteration of the while loop must have been through the "else" clause.
                 // We also know that "part" cannot be "..", otherwise either "growing" is not
@@ -167,7 +335,7 @@ impl ScopedPath {
                 growing = extended;
                 break;
             } else {
-                growing = canonicalize_or_clean(extended)?;
+                growing = canonicalize_or_clean(extended, cache)?;
             }
         }
 
@@ -190,9 +358,20 @@ impl ScopedPath {
             base,
             inner,
             absolute: abs_path.0,
+            relative_input,
+            metadata: OnceCell::new(),
         })
     }
 
+    /// The (cached) metadata of the file/directory this path points at, following a final
+    /// symlink - same semantics as `Path::exists`/`Path::is_dir`. `None` if nothing exists there.
+    /// Stat'd at most once per `ScopedPath`, however many times this is called.
+    pub fn metadata(&self) -> Option<&fs::Metadata> {
+        self.metadata
+            .get_or_init(|| fs::metadata(&self.absolute).ok())
+            .as_ref()
+    }
+
     /// Extract and return the base (parent directory) and name from the "inner" portion (which
     /// may still be absolute).
     ///
@@ -236,6 +415,145 @@ impl ops::Deref for ScopedPath {
     }
 }
 
+/// Platform-reserved device names (Windows, case-insensitive, ignoring any extension).
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+fn is_reserved_name(name: &OsStr) -> bool {
+    match name.to_str() {
+        Some(s) => {
+            let stem = s.split('.').next().unwrap_or(s);
+            RESERVED_NAMES.iter().any(|reserved| stem.eq_ignore_ascii_case(reserved))
+        }
+        None => false,
+    }
+}
+
+fn has_embedded_nul(name: &OsStr) -> bool {
+    match name.to_str() {
+        Some(s) => s.contains('\u{0}'),
+        // Not valid UTF-8, but that alone doesn't make it an invalid path component.
+        None => false,
+    }
+}
+
+/// Why `PathAuditor::audit` rejected a path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathAuditError {
+    /// The path still has a `..` component left after `ScopedPath` normalization, i.e. it escapes
+    /// `base` in a way that cannot be represented as a (possibly absolute) stored path; or the
+    /// path was given relative to `base` but resolves outside it.
+    EscapesBase(PathBuf),
+    /// A directory between `base` and the target is a symlink, so the path that would be stored
+    /// does not actually lead to where it looks like it does.
+    SymlinkTraversal { at: PathBuf },
+    /// A component is a name some platforms (namely Windows) reserve for devices, e.g. `CON`.
+    ReservedName(PathBuf),
+    /// A component contains something no filesystem can store, e.g. an embedded NUL byte.
+    InvalidComponent(PathBuf),
+}
+
+impl fmt::Display for PathAuditError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PathAuditError::EscapesBase(path) => {
+                write!(f, "'{}' escapes the base directory", path.display())
+            }
+            PathAuditError::SymlinkTraversal { at } => write!(
+                f,
+                "'{}' is a symlink, so the path through it cannot be trusted",
+                at.display()
+            ),
+            PathAuditError::ReservedName(name) => {
+                write!(f, "'{}' is a reserved name on some platforms", name.display())
+            }
+            PathAuditError::InvalidComponent(name) => {
+                write!(f, "'{}' is not a valid path component", name.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for PathAuditError {}
+
+impl From<PathAuditError> for Error {
+    fn from(err: PathAuditError) -> Self {
+        err.to_string().into()
+    }
+}
+
+/// Borrows the idea of Mercurial's path auditor: before a path is trusted enough to be inserted
+/// into (or looked up from) the database, walk it component-by-component and check for anything
+/// that would make the stored path unsafe or unable to resolve the way it looks like it should.
+///
+/// Unlike the lightweight symlink check `ScopedPath::new` already does while descending into
+/// `base` (which stops resolving as soon as it hits the first in-root symlink, deliberately
+/// leaving the rest of the path unresolved), this audits *every* prefix between `base` and the
+/// target. Safe prefixes are cached, so auditing many paths under the same parent directory only
+/// re-stats each shared component once.
+pub struct PathAuditor {
+    base: Rc<CanonicalPath>,
+    audited: RefCell<HashSet<PathBuf>>,
+    metadata_cache: MetadataCache,
+}
+
+impl PathAuditor {
+    pub fn new(base: Rc<CanonicalPath>) -> Self {
+        PathAuditor {
+            base,
+            audited: RefCell::new(HashSet::new()),
+            metadata_cache: MetadataCache::new(),
+        }
+    }
+
+    pub fn audit(&self, path: &ScopedPath) -> Result<()> {
+        if path.inner.components().any(|c| c == Component::ParentDir) {
+            return Err(PathAuditError::EscapesBase(path.inner.clone()).into());
+        }
+
+        // A path given as relative is meant to stay under `base`. By the time we get here,
+        // `ScopedPath::new` may already have fully canonicalized it (resolving every `..`) if it
+        // pointed at something that exists on disk, so no literal `..` is left in `inner` even
+        // though the path escapes `base` - the check above can't see that. A path the caller typed
+        // as absolute on purpose is exempt: it was never meant to be confined to `base`.
+        if path.relative_input && path.absolute.strip_prefix(&*self.base).is_err() {
+            return Err(PathAuditError::EscapesBase(path.inner.clone()).into());
+        }
+
+        for component in path.inner.components() {
+            if let Component::Normal(name) = component {
+                if cfg!(windows) && is_reserved_name(name) {
+                    return Err(PathAuditError::ReservedName(PathBuf::from(name)).into());
+                }
+                if has_embedded_nul(name) {
+                    return Err(PathAuditError::InvalidComponent(PathBuf::from(name)).into());
+                }
+            }
+        }
+
+        if let Ok(relative) = path.absolute.strip_prefix(&*self.base) {
+            let mut prefix = self.base.to_path_buf();
+            for component in relative.components() {
+                prefix.push(component);
+
+                if self.audited.borrow().contains(&prefix) {
+                    continue;
+                }
+
+                if is_symlink(&prefix, &self.metadata_cache) {
+                    return Err(PathAuditError::SymlinkTraversal { at: prefix }.into());
+                }
+
+                self.audited.borrow_mut().insert(prefix.clone());
+            }
+        }
+
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -378,4 +696,29 @@ mod tests {
         fs::create_dir_all("/tmp/foo").unwrap();
         assert_deref("/tmp/foo", &PathBuf::from("/tmp/foo"));
     }
+
+    #[test]
+    fn audit_rejects_a_relative_path_that_resolves_outside_base() {
+        let root = join!(TESTS_ROOT, "audit-root");
+        fs::create_dir_all(&root).unwrap();
+        let base = Rc::new(CanonicalPath::new(&root).unwrap());
+        let auditor = PathAuditor::new(base.clone());
+
+        // The target exists, so `ScopedPath::new` fully canonicalizes away the `..` components,
+        // leaving nothing in `inner`/`absolute` to tell this apart from a path the caller typed as
+        // absolute - the auditor must reject it anyway, since the *input* was relative.
+        fs::create_dir_all(TESTS_ROOT).unwrap();
+        let escaping = ScopedPath::new(base.clone(), "../audit-root/../audit-root/../../tmsu-tests").unwrap();
+        let err = auditor.audit(&escaping).unwrap_err();
+        assert!(err.to_string().contains("escapes the base directory"), "{}", err);
+
+        // The very same resolved location, but typed as absolute on purpose: not an escape.
+        let absolute = ScopedPath::new(base.clone(), TESTS_ROOT).unwrap();
+        auditor.audit(&absolute).unwrap();
+
+        // An ordinary path within the base is unaffected.
+        fs::create_dir_all(root.join("inside")).unwrap();
+        let inside = ScopedPath::new(base, "inside").unwrap();
+        auditor.audit(&inside).unwrap();
+    }
 }
\ No newline at end of file
@@ -1,6 +1,10 @@
+pub mod changeset;
 pub mod file;
 pub mod filetag;
 pub mod implication;
+#[cfg(test)]
+pub(crate) mod mem;
+pub mod rule;
 mod schema;
 pub mod setting;
 pub mod tag;
@@ -10,9 +14,13 @@ pub mod value;
 use std::iter;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+
+use rusqlite::backup::{Backup, StepResult};
 
 use crate::entities::path::{CanonicalPath, ScopedPath};
-use crate::entities::{FileId, TagId, ValueId};
+use crate::entities::{FileId, FileTag, Tag, TagId, Value, ValueId};
 use crate::errors::*;
 
 pub struct Storage {
@@ -27,21 +35,31 @@ pub struct Storage {
 impl Storage {
     pub fn create_at(db_path: &Path) -> Result<()> {
         info!("Creating database at {}", db_path.display());
-        Self::create_or_open(db_path)?;
+        Self::create_or_open(db_path, StorageOptions::default())?;
         Ok(())
     }
 
     pub fn open(db_path: &Path) -> Result<Self> {
+        Self::open_with_options(db_path, StorageOptions::default())
+    }
+
+    /// Like `open`, but lets the caller tune the durability-vs-throughput pragmas set up below -
+    /// e.g. a bulk import path can pass `Synchronous::Off` to skip `fsync`s, at the cost of
+    /// corruption risk on power loss, which is an acceptable trade when the import can just be
+    /// re-run.
+    pub fn open_with_options(db_path: &Path, options: StorageOptions) -> Result<Self> {
         info!("Opening database at {}", db_path.display());
-        Self::create_or_open(db_path)
+        Self::create_or_open(db_path, options)
     }
 
     /// Open a sqlite3 DB file, also creating it if it doesn't already exist.
     /// Note that the parent directory will NOT be created if it doesn't exist.
-    fn create_or_open(db_path: &Path) -> Result<Self> {
+    fn create_or_open(db_path: &Path, options: StorageOptions) -> Result<Self> {
         let conn = rusqlite::Connection::open(&db_path)
             .map_err(|_| ErrorKind::DatabaseAccessError(db_path.to_path_buf()))?;
 
+        configure_pragmas(&conn, &options)?;
+
         // Use a canonical path to avoid issues such as #168
         let db_path = db_path
             .canonicalize()
@@ -58,6 +76,42 @@ impl Storage {
         Ok(res)
     }
 
+    /// Open a sqlite3 DB file read-only: no schema upgrade is attempted (a read-only connection
+    /// couldn't write one anyway), and no write ever reaches the file, so several query-only
+    /// processes (`tmsu files`, `tmsu tags`, ...) can safely share a database on read-only media or
+    /// a network mount without one of them grabbing a write lock.
+    ///
+    /// Errors clearly if the on-disk schema is older than what this binary expects, rather than
+    /// letting queries fail confusingly against missing tables/columns.
+    pub fn open_readonly(db_path: &Path) -> Result<Self> {
+        info!("Opening database read-only at {}", db_path.display());
+
+        let conn = rusqlite::Connection::open_with_flags(&db_path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .map_err(|_| ErrorKind::DatabaseAccessError(db_path.to_path_buf()))?;
+
+        // Use a canonical path to avoid issues such as #168
+        let db_path = db_path
+            .canonicalize()
+            .map_err(|_| ErrorKind::NoDatabaseFound(db_path.to_path_buf()))?;
+
+        let mut res = Storage {
+            root_path: Rc::new(CanonicalPath::new(determine_root_path(&db_path)?)?),
+            db_path: CanonicalPath::new(db_path)?,
+            conn,
+        };
+
+        let mut tx = res.begin_transaction()?;
+        let up_to_date = upgrade::is_up_to_date(&mut tx)?;
+        tx.commit()?;
+
+        error_chain::ensure!(
+            up_to_date,
+            "database schema is out of date; open it read-write once (e.g. run any other tmsu command) to upgrade it"
+        );
+
+        Ok(res)
+    }
+
     pub fn begin_transaction<'a>(&'a mut self) -> Result<Transaction<'a>> {
         Ok(Transaction {
             tx: self.conn.transaction()?,
@@ -81,6 +135,102 @@ impl Storage {
         let scoped = ScopedPath::new(Rc::new(canonical), self.root_path.as_ref())?;
         Ok(scoped.inner().is_relative())
     }
+
+    /// Take an online (hot) backup of this database into `dest`, safe to run while other TMSU
+    /// processes have the database open - even mid-transaction - since it goes through SQLite's
+    /// backup API instead of copying the file directly. A plain filesystem copy could otherwise
+    /// capture a half-written file, or miss data still sitting in the WAL/journal.
+    ///
+    /// `progress` is called after every step that copies pages (not after a busy/locked step) with
+    /// `(remaining, total)` pages, so a caller (e.g.
+    /// the `backup` subcommand) can print a percentage.
+    pub fn backup_to(&mut self, dest: &Path, mut progress: impl FnMut(u32, u32)) -> Result<()> {
+        const PAGES_PER_STEP: i32 = 64;
+        const BUSY_SLEEP: Duration = Duration::from_millis(100);
+
+        let mut dest_conn = rusqlite::Connection::open(dest)?;
+        let backup = Backup::new(&self.conn, &mut dest_conn)?;
+
+        loop {
+            match backup.step(PAGES_PER_STEP)? {
+                StepResult::Done => {
+                    progress(0, backup.progress().pagecount as u32);
+                    break;
+                }
+                StepResult::More => {
+                    let p = backup.progress();
+                    progress(p.remaining as u32, p.pagecount as u32);
+                }
+                StepResult::Busy | StepResult::Locked => {
+                    thread::sleep(BUSY_SLEEP);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Run `f` while recording every change it makes to the taggable tables, and return the
+    /// result as a portable changeset - see `storage::changeset` for why this, rather than a
+    /// `backup_to` copy, is what lets two independently-tagged repositories sync with each other.
+    pub fn record_changeset(&mut self, f: impl FnOnce(&mut Transaction) -> Result<()>) -> Result<Vec<u8>> {
+        changeset::record(self, f)
+    }
+
+    /// Apply a changeset produced by `record_changeset` on another database. See
+    /// `storage::changeset` for how the changeset's ids are resolved against this database.
+    pub fn apply_changeset(
+        &mut self,
+        bundle: &[u8],
+        conflict_policy: changeset::ConflictPolicy,
+    ) -> Result<Vec<changeset::MergeConflict>> {
+        let mut tx = self.begin_transaction()?;
+        let conflicts = changeset::apply(&mut tx, bundle, conflict_policy)?;
+        tx.commit()?;
+        Ok(conflicts)
+    }
+}
+
+/// How strictly SQLite should `fsync` on commit; see the `synchronous` pragma. `Normal` is safe
+/// under WAL (the default journal mode set up in `configure_pragmas`) and is the right choice for
+/// everyday use; `Off` skips those fsyncs entirely, trading durability for throughput on bulk
+/// operations that can simply be re-run if interrupted (e.g. a large `--fast` import).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Synchronous {
+    Off,
+    Normal,
+    Full,
+}
+
+impl Synchronous {
+    fn pragma_value(self) -> &'static str {
+        match self {
+            Synchronous::Off => "OFF",
+            Synchronous::Normal => "NORMAL",
+            Synchronous::Full => "FULL",
+        }
+    }
+}
+
+impl Default for Synchronous {
+    fn default() -> Self {
+        Synchronous::Normal
+    }
+}
+
+/// Tunable pragmas applied when opening a database; see `Storage::open_with_options`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StorageOptions {
+    pub synchronous: Synchronous,
+}
+
+/// Put the connection into WAL journal mode (better read/write concurrency, and no longer needs a
+/// full `fsync` under the default `synchronous=NORMAL`) and apply the requested `synchronous`
+/// level.
+fn configure_pragmas(conn: &rusqlite::Connection, options: &StorageOptions) -> Result<()> {
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+    conn.pragma_update(None, "synchronous", options.synchronous.pragma_value())?;
+    Ok(())
 }
 
 fn determine_root_path(db_path: &Path) -> Result<PathBuf> {
@@ -131,7 +281,40 @@ impl<'a> Transaction<'a> {
         P: IntoIterator,
         P::Item: rusqlite::ToSql,
     {
-        Ok(self.tx.execute(sql, params)?)
+        self.execute_params_maybe_cached(sql, params, true)
+    }
+
+    /// Like `execute_params`, but lets the caller skip the statement cache - see
+    /// `prepare_maybe_cached` for why a variable-sized SQL text (e.g. a multi-row `INSERT ...
+    /// VALUES (...), (...), ...`) should pass `cached: false`.
+    fn execute_params_maybe_cached<P>(&mut self, sql: &str, params: P, cached: bool) -> Result<usize>
+    where
+        P: IntoIterator,
+        P::Item: rusqlite::ToSql,
+    {
+        self.prepare_maybe_cached(sql, cached, |stmt| Ok(stmt.execute(params)?))
+    }
+
+    /// Prepare `sql` and hand it to `body`, going through rusqlite's statement cache unless
+    /// `cached` is false.
+    ///
+    /// Statements are keyed on their literal SQL text, so caching only helps when the same text is
+    /// prepared repeatedly (e.g. `insert_tag`/`tag_by_name` called once per tagged file). SQL built
+    /// up from a variable-sized piece - like `query_vec_chunked`'s `IN (?,?,...)` placeholder list,
+    /// which is a different string for every chunk size - should pass `cached: false` instead, or
+    /// it would just fill the cache with statements that are never prepared again. `CachedStatement`
+    /// derefs to `Statement`, so both branches can hand `body` the same `&mut rusqlite::Statement`.
+    fn prepare_maybe_cached<R>(
+        &mut self,
+        sql: &str,
+        cached: bool,
+        body: impl FnOnce(&mut rusqlite::Statement) -> Result<R>,
+    ) -> Result<R> {
+        if cached {
+            body(&mut self.tx.prepare_cached(sql)?)
+        } else {
+            body(&mut self.tx.prepare(sql)?)
+        }
     }
 
     /// Execute a query and create one object per returned line.
@@ -151,15 +334,33 @@ impl<'a> Transaction<'a> {
         P::Item: rusqlite::ToSql,
         F: Fn(Row<'_>) -> Result<T>,
     {
-        let mut stmt = self.tx.prepare(sql)?;
-        let mut rows = stmt.query(params)?;
+        self.query_vec_params_maybe_cached(sql, params, true, f)
+    }
+
+    /// Like `query_vec_params`, but lets the caller skip the statement cache - see
+    /// `query_vec_chunked`, the one caller that needs this, for why.
+    fn query_vec_params_maybe_cached<T, P, F>(
+        &mut self,
+        sql: &str,
+        params: P,
+        cached: bool,
+        f: F,
+    ) -> Result<Vec<T>>
+    where
+        P: IntoIterator,
+        P::Item: rusqlite::ToSql,
+        F: Fn(Row<'_>) -> Result<T>,
+    {
+        self.prepare_maybe_cached(sql, cached, |stmt| {
+            let mut rows = stmt.query(params)?;
 
-        let mut objects = Vec::new();
-        while let Some(row) = rows.next()? {
-            objects.push(f(Row::new(row))?);
-        }
+            let mut objects = Vec::new();
+            while let Some(row) = rows.next()? {
+                objects.push(f(Row::new(row))?);
+            }
 
-        Ok(objects)
+            Ok(objects)
+        })
     }
 
     fn query_single<T, F>(&mut self, sql: &str, f: F) -> Result<Option<T>>
@@ -175,10 +376,10 @@ impl<'a> Transaction<'a> {
         P::Item: rusqlite::ToSql,
         F: FnOnce(Row<'_>) -> Result<T>,
     {
-        let mut stmt = self.tx.prepare(sql)?;
-        let mut rows = stmt.query(params)?;
-
-        rows.next()?.map(|r| Row::new(r)).map(f).transpose()
+        self.prepare_maybe_cached(sql, true, |stmt| {
+            let mut rows = stmt.query(params)?;
+            rows.next()?.map(|r| Row::new(r)).map(f).transpose()
+        })
     }
 
     fn count_from_table(&mut self, table_name: &str) -> Result<u64> {
@@ -196,6 +397,161 @@ FROM {}",
     fn last_inserted_row_id(&mut self) -> u32 {
         self.tx.last_insert_rowid() as u32
     }
+
+    /// Run a `... IN (?,?,...)`-shaped query over `items`, chunking them into groups of at most
+    /// `chunk_size` so the number of bound parameters in any one statement stays under SQLite's
+    /// `SQLITE_MAX_VARIABLE_NUMBER` limit (historically 999). `sql_prefix` should be the query up
+    /// to (but not including) the placeholder list, e.g. `"SELECT id, name FROM tag WHERE name IN"`;
+    /// the placeholders are regenerated for each chunk, since the final chunk is usually partial.
+    ///
+    /// Results are concatenated in chunk order; within a chunk they follow whatever order the
+    /// query returns them in (not necessarily the order of `items`). An empty `items` short-circuits
+    /// to an empty `Vec` without touching the database.
+    fn query_vec_chunked<T, F>(
+        &mut self,
+        sql_prefix: &str,
+        items: &[&str],
+        chunk_size: usize,
+        f: F,
+    ) -> Result<Vec<T>>
+    where
+        F: Fn(Row<'_>) -> Result<T>,
+    {
+        if items.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut objects = Vec::with_capacity(items.len());
+        for chunk in items.chunks(chunk_size) {
+            let (placeholders, params) = generate_placeholders(chunk)?;
+            let sql = format!("{} ({})", sql_prefix, placeholders);
+            // Uncached: the placeholder list makes `sql` a different string for every chunk size,
+            // so caching it would just displace genuinely-reusable statements from the cache.
+            objects.extend(self.query_vec_params_maybe_cached(&sql, &params, false, &f)?);
+        }
+
+        Ok(objects)
+    }
+}
+
+/// Default chunk size for `Transaction::query_vec_chunked`: comfortably under SQLite's historical
+/// `SQLITE_MAX_VARIABLE_NUMBER` ceiling of 999, leaving headroom for a query's other parameters.
+const MAX_CHUNKED_QUERY_PARAMS: usize = 900;
+
+/// Row-level operations on tags, independent of the backing store. Implemented by `Transaction`
+/// for production use, and by an in-memory store (see `storage::mem`, test-only) so command logic
+/// built on top of these traits can be exercised without a real sqlite database.
+pub trait TagStore {
+    fn tags_by_names(&mut self, names: &[&str]) -> Result<Vec<Tag>>;
+    fn tag_by_name(&mut self, name: &str) -> Result<Option<Tag>>;
+    fn tag_by_id(&mut self, tag_id: &TagId) -> Result<Option<Tag>>;
+    fn insert_tag(&mut self, name: &str) -> Result<Tag>;
+    fn rename_tag(&mut self, tag_id: &TagId, name: &str) -> Result<()>;
+    fn delete_tag(&mut self, tag_id: &TagId) -> Result<()>;
+}
+
+/// Row-level operations on values. See `TagStore` for the rationale behind this trait split.
+pub trait ValueStore {
+    fn values(&mut self) -> Result<Vec<Value>>;
+    fn values_by_names(&mut self, names: &[&str]) -> Result<Vec<Value>>;
+    fn value_by_name(&mut self, name: &str) -> Result<Option<Value>>;
+    fn insert_value(&mut self, name: &str) -> Result<Value>;
+    fn rename_value(&mut self, value_id: &ValueId, name: &str) -> Result<()>;
+    fn delete_value(&mut self, value_id: &ValueId) -> Result<()>;
+}
+
+/// Row-level operations on file-tag associations. See `TagStore` for the rationale behind this
+/// trait split.
+pub trait FileTagStore {
+    fn file_tags_by_tag_id(&mut self, tag_id: &TagId) -> Result<Vec<FileTag>>;
+    fn file_tags_by_value_id(&mut self, value_id: &ValueId) -> Result<Vec<FileTag>>;
+    fn add_file_tag(
+        &mut self,
+        file_id: FileId,
+        tag_id: TagId,
+        value_id: Option<ValueId>,
+    ) -> Result<usize>;
+    fn delete_file_tags_by_tag_id(&mut self, tag_id: &TagId) -> Result<usize>;
+    fn delete_file_tags_by_value_id(&mut self, value_id: &ValueId) -> Result<usize>;
+}
+
+impl<'a> TagStore for Transaction<'a> {
+    fn tags_by_names(&mut self, names: &[&str]) -> Result<Vec<Tag>> {
+        tag::tags_by_names(self, names)
+    }
+
+    fn tag_by_name(&mut self, name: &str) -> Result<Option<Tag>> {
+        tag::tag_by_name(self, name)
+    }
+
+    fn tag_by_id(&mut self, tag_id: &TagId) -> Result<Option<Tag>> {
+        tag::tag_by_id(self, tag_id)
+    }
+
+    fn insert_tag(&mut self, name: &str) -> Result<Tag> {
+        tag::insert_tag(self, name)
+    }
+
+    fn rename_tag(&mut self, tag_id: &TagId, name: &str) -> Result<()> {
+        tag::rename_tag(self, tag_id, name)
+    }
+
+    fn delete_tag(&mut self, tag_id: &TagId) -> Result<()> {
+        tag::delete_tag(self, tag_id)
+    }
+}
+
+impl<'a> ValueStore for Transaction<'a> {
+    fn values(&mut self) -> Result<Vec<Value>> {
+        value::values(self)
+    }
+
+    fn values_by_names(&mut self, names: &[&str]) -> Result<Vec<Value>> {
+        value::values_by_names(self, names)
+    }
+
+    fn value_by_name(&mut self, name: &str) -> Result<Option<Value>> {
+        value::value_by_name(self, name)
+    }
+
+    fn insert_value(&mut self, name: &str) -> Result<Value> {
+        value::insert_value(self, name)
+    }
+
+    fn rename_value(&mut self, value_id: &ValueId, name: &str) -> Result<()> {
+        value::rename_value(self, value_id, name)
+    }
+
+    fn delete_value(&mut self, value_id: &ValueId) -> Result<()> {
+        value::delete_value(self, value_id)
+    }
+}
+
+impl<'a> FileTagStore for Transaction<'a> {
+    fn file_tags_by_tag_id(&mut self, tag_id: &TagId) -> Result<Vec<FileTag>> {
+        filetag::file_tags_by_tag_id(self, tag_id)
+    }
+
+    fn file_tags_by_value_id(&mut self, value_id: &ValueId) -> Result<Vec<FileTag>> {
+        filetag::file_tags_by_value_id(self, value_id)
+    }
+
+    fn add_file_tag(
+        &mut self,
+        file_id: FileId,
+        tag_id: TagId,
+        value_id: Option<ValueId>,
+    ) -> Result<usize> {
+        filetag::add_file_tag(self, file_id, tag_id, value_id)
+    }
+
+    fn delete_file_tags_by_tag_id(&mut self, tag_id: &TagId) -> Result<usize> {
+        filetag::delete_file_tags_by_tag_id(self, tag_id)
+    }
+
+    fn delete_file_tags_by_value_id(&mut self, value_id: &ValueId) -> Result<usize> {
+        filetag::delete_file_tags_by_value_id(self, value_id)
+    }
 }
 
 /// Generate a string such as "?,?,?", with as many placeholders ('?') as requested
@@ -212,6 +568,18 @@ fn generate_placeholders<'a>(values: &'a [&str]) -> Result<(String, Vec<&'a dyn
     Ok((placeholders.join(","), params))
 }
 
+/// Generate a string such as "(?,?,?),(?,?,?)": `num_groups` comma-separated groups, each
+/// containing `group_size` placeholders. Used for multi-row `INSERT ... VALUES (...),(...)`
+/// statements.
+fn generate_placeholder_groups(num_groups: usize, group_size: usize) -> String {
+    let group = format!(
+        "({})",
+        iter::repeat("?").take(group_size).collect::<Vec<_>>().join(",")
+    );
+
+    iter::repeat(group).take(num_groups).collect::<Vec<_>>().join(",")
+}
+
 /// Convert a path-like object into a string. Note that this conversion can fail.
 /// TODO: does this really work on Windows? If not, what to do instead?
 fn path_to_sql<'a, P: 'a + AsRef<Path>>(path: P) -> Result<String> {
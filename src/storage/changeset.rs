@@ -0,0 +1,339 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+use rusqlite::hooks::Action;
+use rusqlite::session::{ChangesetIter, ChangesetItem, Session};
+use rusqlite::types::ValueRef;
+
+use crate::entities::{FileId, TagId, ValueId};
+use crate::errors::*;
+use crate::storage::{self, Storage, Transaction};
+
+/// Tables whose rows make up TMSU's "tagging decisions" - as opposed to `file`, whose rows are
+/// filesystem metadata that two machines will naturally disagree about (mod times, sizes, ...).
+/// Syncing only these is what lets `record`/`apply` replicate *tags* between repositories without
+/// also trying to reconcile which files each side happens to have indexed.
+const CHANGESET_TABLES: &[&str] = &["tag", "value", "file_tag", "implication"];
+
+/// What to do when an incoming row can't be reconciled with local state - e.g. it references a
+/// tag/value/file that doesn't exist locally, or a `delete` targets a row that's already gone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Fail the whole `apply` at the first unreconcilable row.
+    Abort,
+    /// Skip the row, record it as a `MergeConflict`, and keep applying the rest.
+    Replace,
+}
+
+/// One row from an incoming changeset that `apply` couldn't reconcile, returned so a caller (e.g.
+/// a `sync` subcommand) can report what was skipped.
+#[derive(Debug)]
+pub struct MergeConflict {
+    pub table: String,
+    pub detail: String,
+}
+
+/// Run `f` against a fresh transaction while a SQLite session records every row it touches in
+/// `CHANGESET_TABLES`, then return the recorded changeset as an opaque, portable byte string.
+///
+/// TMSU's `tag`/`value`/`file`/`file_tag` ids are surrogate keys, local to the database that
+/// assigned them - two repositories that have each tagged `photo.jpg` as `holiday` agree on the
+/// *tagging*, but almost certainly not on the numeric ids involved. A raw SQLite changeset only
+/// carries those numeric ids, so on its own it isn't enough to apply on a different database: see
+/// `apply` for how the bundle returned here carries the extra information needed to resolve it.
+pub fn record(storage: &mut Storage, f: impl FnOnce(&mut Transaction) -> Result<()>) -> Result<Vec<u8>> {
+    let mut session = Session::new(&storage.conn)?;
+    for table in CHANGESET_TABLES {
+        session.attach(Some(table))?;
+    }
+
+    {
+        let mut tx = storage.begin_transaction()?;
+        f(&mut tx)?;
+        tx.commit()?;
+    }
+
+    let mut raw_changeset = Vec::new();
+    session.changeset_strm(&mut raw_changeset)?;
+
+    let identities = {
+        let mut tx = storage.begin_transaction()?;
+        let map = snapshot_identities(&mut tx)?;
+        tx.commit()?;
+        map
+    };
+
+    Ok(encode_bundle(&raw_changeset, &identities))
+}
+
+/// Apply a bundle produced by `record` against `tx`, resolving every `tag_id`/`value_id`/
+/// `file_id` it references through the natural key (`tag.name`, `value.name`, a file's
+/// directory+name) that id stood for in the *source* database, rather than trusting the raw id -
+/// which would either collide with an unrelated local row or silently create a duplicate.
+pub fn apply(tx: &mut Transaction, bundle: &[u8], conflict_policy: ConflictPolicy) -> Result<Vec<MergeConflict>> {
+    let (raw_changeset, identities) = decode_bundle(bundle)?;
+
+    let mut iter = ChangesetIter::start_strm(&mut &raw_changeset[..])?;
+    let mut conflicts = Vec::new();
+
+    while let Some(item) = iter.next()? {
+        let table = item.table_name()?.to_owned();
+
+        let result = match table.as_str() {
+            "tag" => apply_tag_change(tx, &item),
+            "value" => apply_value_change(tx, &item),
+            "file_tag" => apply_file_tag_change(tx, &item, &identities),
+            "implication" => apply_implication_change(tx, &item, &identities),
+            other => Err(format!("unexpected table '{}' in changeset", other).into()),
+        };
+
+        if let Err(e) = result {
+            match conflict_policy {
+                ConflictPolicy::Abort => return Err(e),
+                ConflictPolicy::Replace => conflicts.push(MergeConflict {
+                    table,
+                    detail: e.to_string(),
+                }),
+            }
+        }
+    }
+
+    Ok(conflicts)
+}
+
+fn apply_tag_change(tx: &mut Transaction, item: &ChangesetItem) -> Result<()> {
+    match item.op()? {
+        Action::SQLITE_DELETE => {
+            let name = column_str(item.old_value(1))?;
+            if let Some(tag) = storage::tag::tag_by_name(tx, &name)? {
+                storage::tag::delete_tag(tx, &tag.id)?;
+            }
+        }
+        _ => {
+            let name = column_str(item.new_value(1))?;
+            if storage::tag::tag_by_name(tx, &name)?.is_none() {
+                storage::tag::insert_tag(tx, &name)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn apply_value_change(tx: &mut Transaction, item: &ChangesetItem) -> Result<()> {
+    match item.op()? {
+        Action::SQLITE_DELETE => {
+            let name = column_str(item.old_value(1))?;
+            if let Some(value) = storage::value::value_by_name(tx, &name)? {
+                storage::value::delete_value(tx, &value.id)?;
+            }
+        }
+        _ => {
+            let name = column_str(item.new_value(1))?;
+            if storage::value::value_by_name(tx, &name)?.is_none() {
+                storage::value::insert_value(tx, &name)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn apply_file_tag_change(tx: &mut Transaction, item: &ChangesetItem, identities: &Identities) -> Result<()> {
+    let is_delete = item.op()? == Action::SQLITE_DELETE;
+    let get = |i: usize| if is_delete { item.old_value(i) } else { item.new_value(i) };
+
+    let file_id = resolve_file(tx, identities, FileId(column_u32(get(0))?))?;
+    let tag_id = resolve_tag(tx, identities, TagId(column_u32(get(1))?))?;
+    let value_id = resolve_value(tx, identities, ValueId(column_u32(get(2))?))?;
+
+    if is_delete {
+        storage::filetag::delete_file_tag(tx, file_id, tag_id, value_id)?;
+    } else {
+        storage::filetag::add_file_tag(tx, file_id, tag_id, value_id)?;
+    }
+
+    Ok(())
+}
+
+fn apply_implication_change(tx: &mut Transaction, item: &ChangesetItem, identities: &Identities) -> Result<()> {
+    // Schema: implication(tag_id, value_id, implied_tag_id, implied_value_id)
+    let is_delete = item.op()? == Action::SQLITE_DELETE;
+    let get = |i: usize| if is_delete { item.old_value(i) } else { item.new_value(i) };
+
+    let tag_id = resolve_tag(tx, identities, TagId(column_u32(get(0))?))?;
+    let value_id = resolve_value(tx, identities, ValueId(column_u32(get(1))?))?;
+    let implied_tag_id = resolve_tag(tx, identities, TagId(column_u32(get(2))?))?;
+    let implied_value_id = resolve_value(tx, identities, ValueId(column_u32(get(3))?))?;
+
+    if is_delete {
+        storage::implication::delete_implication(tx, &tag_id, value_id, &implied_tag_id, implied_value_id)?;
+    } else {
+        storage::implication::insert_implication(tx, tag_id, value_id, implied_tag_id, implied_value_id)?;
+    }
+
+    Ok(())
+}
+
+fn resolve_tag(tx: &mut Transaction, identities: &Identities, remote_id: TagId) -> Result<TagId> {
+    let name = identities
+        .tag_names
+        .get(&remote_id)
+        .ok_or_else(|| format!("changeset references unknown tag id {}", remote_id).into())?;
+
+    storage::tag::tag_by_name(tx, name)?
+        .map(|tag| tag.id)
+        .ok_or_else(|| format!("tag '{}' does not exist locally", name).into())
+}
+
+fn resolve_value(tx: &mut Transaction, identities: &Identities, remote_id: ValueId) -> Result<Option<ValueId>> {
+    if remote_id == ValueId(0) {
+        return Ok(None);
+    }
+
+    let name = identities
+        .value_names
+        .get(&remote_id)
+        .ok_or_else(|| format!("changeset references unknown value id {}", remote_id).into())?;
+
+    storage::value::value_by_name(tx, name)?
+        .map(|value| Some(value.id))
+        .ok_or_else(|| format!("value '{}' does not exist locally", name).into())
+}
+
+fn resolve_file(tx: &mut Transaction, identities: &Identities, remote_id: FileId) -> Result<FileId> {
+    let (dir, name) = identities
+        .file_paths
+        .get(&remote_id)
+        .ok_or_else(|| format!("changeset references unknown file id {}", remote_id.0).into())?;
+
+    storage::file::file_by_dir_and_name(tx, dir, name)?
+        .map(|file| file.id)
+        .ok_or_else(|| format!("'{}/{}' is not tracked locally", dir, name).into())
+}
+
+fn column_u32(value: Option<ValueRef>) -> Result<u32> {
+    match value {
+        Some(ValueRef::Integer(i)) => Ok(i as u32),
+        _ => Err("expected an integer column in changeset".into()),
+    }
+}
+
+fn column_str(value: Option<ValueRef>) -> Result<String> {
+    match value {
+        Some(ValueRef::Text(bytes)) => Ok(std::str::from_utf8(bytes)?.to_owned()),
+        _ => Err("expected a text column in changeset".into()),
+    }
+}
+
+/// Snapshot of every id -> natural key mapping an incoming `file_tag`/`implication` change might
+/// need to resolve. Taken from the *whole* table rather than just the rows the changeset touched,
+/// since a `file_tag` change can reference a `tag`/`file` row that itself didn't change.
+struct Identities {
+    tag_names: HashMap<TagId, String>,
+    value_names: HashMap<ValueId, String>,
+    file_paths: HashMap<FileId, (String, String)>, // (directory, name)
+}
+
+fn snapshot_identities(tx: &mut Transaction) -> Result<Identities> {
+    let tag_names = storage::tag::tags(tx)?
+        .into_iter()
+        .map(|tag| (tag.id, tag.name))
+        .collect();
+    let value_names = storage::value::values(tx)?
+        .into_iter()
+        .map(|value| (value.id, value.name))
+        .collect();
+    let file_paths = storage::file::files(tx)?
+        .into_iter()
+        .map(|file| (file.id, (file.dir, file.name)))
+        .collect();
+
+    Ok(Identities {
+        tag_names,
+        value_names,
+        file_paths,
+    })
+}
+
+/// Bundle the raw SQLite changeset together with the identity snapshot it needs to be resolved
+/// against a different database: `[4-byte LE length][raw changeset bytes][identity records]`.
+/// Deliberately not reusing `api::dump`'s text format here - storage is a lower layer than api and
+/// shouldn't depend on it - so the identity records get their own (equally small) encoding below.
+fn encode_bundle(raw_changeset: &[u8], identities: &Identities) -> Vec<u8> {
+    let mut bundle = Vec::with_capacity(raw_changeset.len() + 64);
+    bundle.extend_from_slice(&(raw_changeset.len() as u32).to_le_bytes());
+    bundle.extend_from_slice(raw_changeset);
+
+    for (id, name) in &identities.tag_names {
+        bundle.extend_from_slice(format!("TAG\t{}\t{}\n", id, escape(name)).as_bytes());
+    }
+    for (id, name) in &identities.value_names {
+        bundle.extend_from_slice(format!("VALUE\t{}\t{}\n", id, escape(name)).as_bytes());
+    }
+    for (id, (dir, name)) in &identities.file_paths {
+        bundle.extend_from_slice(format!("FILE\t{}\t{}\t{}\n", id.0, escape(dir), escape(name)).as_bytes());
+    }
+
+    bundle
+}
+
+fn decode_bundle(bundle: &[u8]) -> Result<(Vec<u8>, Identities)> {
+    error_chain::ensure!(bundle.len() >= 4, "changeset bundle is truncated");
+
+    let changeset_len = u32::from_le_bytes(bundle[..4].try_into().unwrap()) as usize;
+    error_chain::ensure!(bundle.len() >= 4 + changeset_len, "changeset bundle is truncated");
+
+    let raw_changeset = bundle[4..4 + changeset_len].to_vec();
+    let identity_records = std::str::from_utf8(&bundle[4 + changeset_len..])?;
+
+    let mut identities = Identities {
+        tag_names: HashMap::new(),
+        value_names: HashMap::new(),
+        file_paths: HashMap::new(),
+    };
+
+    for line in identity_records.lines() {
+        let fields: Vec<&str> = line.split('\t').collect();
+        match fields.as_slice() {
+            ["TAG", id, name] => {
+                identities.tag_names.insert(TagId(id.parse()?), unescape(name));
+            }
+            ["VALUE", id, name] => {
+                identities.value_names.insert(ValueId(id.parse()?), unescape(name));
+            }
+            ["FILE", id, dir, name] => {
+                identities
+                    .file_paths
+                    .insert(FileId(id.parse()?), (unescape(dir), unescape(name)));
+            }
+            _ => return Err(format!("unrecognized changeset identity record '{}'", line).into()),
+        }
+    }
+
+    Ok((raw_changeset, identities))
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n")
+}
+
+fn unescape(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('t') => result.push('\t'),
+            Some('n') => result.push('\n'),
+            Some(other) => result.push(other),
+            None => {}
+        }
+    }
+
+    result
+}
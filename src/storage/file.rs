@@ -1,4 +1,4 @@
-use chrono::DateTime;
+use chrono::{DateTime, FixedOffset};
 
 use crate::entities::{path::ScopedPath, File, FileId};
 use crate::errors::*;
@@ -10,6 +10,15 @@ pub fn file_count(tx: &mut Transaction) -> Result<u64> {
     tx.count_from_table("file")
 }
 
+pub fn files(tx: &mut Transaction) -> Result<Vec<File>> {
+    let sql = "
+SELECT id, directory, name, fingerprint, mod_time, size, is_dir
+FROM file
+ORDER BY directory, name";
+
+    tx.query_vec(sql, parse_file)
+}
+
 pub fn file_by_path(tx: &mut Transaction, scoped_path: &ScopedPath) -> Result<Option<File>> {
     let sql = "
 SELECT id, directory, name, fingerprint, mod_time, size, is_dir
@@ -22,6 +31,19 @@ WHERE directory = ? AND name = ?";
     tx.query_single_params(sql, params, parse_file)
 }
 
+/// Like `file_by_path`, but for callers that already have `directory`/`name` as plain strings
+/// instead of a `ScopedPath` - e.g. resolving a file referenced by a changeset applied against a
+/// different database, where there is no local root to scope the path against.
+pub fn file_by_dir_and_name(tx: &mut Transaction, dir: &str, name: &str) -> Result<Option<File>> {
+    let sql = "
+SELECT id, directory, name, fingerprint, mod_time, size, is_dir
+FROM file
+WHERE directory = ? AND name = ?";
+
+    let params = rusqlite::params![dir, name];
+    tx.query_single_params(sql, params, parse_file)
+}
+
 fn parse_file(row: Row) -> Result<File> {
     let mod_time_str: String = row.get(4)?;
     let mod_time = DateTime::parse_from_str(&mod_time_str, TIMESTAMP_FORMAT)?;
@@ -37,6 +59,43 @@ fn parse_file(row: Row) -> Result<File> {
     })
 }
 
+/// Insert a file record with an explicit fingerprint/mod_time/size, as opposed to deriving them
+/// from the filesystem. Used when reconstructing a database from a dump.
+pub fn insert_file(
+    tx: &mut Transaction,
+    dir: &str,
+    name: &str,
+    fingerprint: &str,
+    mod_time: DateTime<FixedOffset>,
+    size: usize,
+    is_dir: bool,
+) -> Result<File> {
+    let sql = "
+INSERT INTO file (directory, name, fingerprint, mod_time, size, is_dir)
+VALUES (?, ?, ?, ?, ?, ?)";
+
+    let params = rusqlite::params![
+        dir,
+        name,
+        fingerprint,
+        mod_time.format(TIMESTAMP_FORMAT).to_string(),
+        size as i64,
+        is_dir,
+    ];
+    tx.execute_params(sql, params)?;
+
+    let file_id = tx.last_inserted_row_id();
+    Ok(File {
+        id: FileId(file_id),
+        dir: dir.to_owned(),
+        name: name.to_owned(),
+        fingerprint: fingerprint.to_owned(),
+        mod_time,
+        size,
+        is_dir,
+    })
+}
+
 pub fn delete_untagged_files(tx: &mut Transaction, file_ids: &[FileId]) -> Result<()> {
     let sql = "
 DELETE FROM file
@@ -1,11 +1,24 @@
 use crate::entities::{FileId, FileTag, TagId, ValueId};
 use crate::errors::*;
-use crate::storage::{Row, Transaction};
+use crate::storage::{self, Row, Transaction};
+
+/// Conservative estimate of SQLite's SQLITE_MAX_VARIABLE_NUMBER: the historical default of 999,
+/// which newer SQLite builds raise.
+const SQLITE_MAX_VARIABLES: usize = 999;
+const BINDINGS_PER_ROW: usize = 3;
 
 pub fn file_tag_count(tx: &mut Transaction) -> Result<u64> {
     tx.count_from_table("file_tag")
 }
 
+pub fn file_tags(tx: &mut Transaction) -> Result<Vec<FileTag>> {
+    let sql = "
+SELECT file_id, tag_id, value_id
+FROM file_tag";
+
+    tx.query_vec(sql, parse_file_tag)
+}
+
 pub fn file_tags_by_tag_id(tx: &mut Transaction, tag_id: &TagId) -> Result<Vec<FileTag>> {
     let sql = "
 SELECT file_id, tag_id, value_id
@@ -62,6 +75,73 @@ VALUES (?1, ?2, ?3)";
     tx.execute_params(sql, params)
 }
 
+/// Insert many (file, tag, value) rows in one go, building chunked multi-row `VALUES` statements
+/// so the bound-parameter count never exceeds SQLite's limit. Mirrors the `INSERT OR IGNORE`
+/// semantics of `add_file_tag`, including the value-ID-0-means-no-value convention.
+pub fn add_file_tags(tx: &mut Transaction, rows: &[(FileId, TagId, Option<ValueId>)]) -> Result<usize> {
+    if rows.is_empty() {
+        return Ok(0);
+    }
+
+    let chunk_size = SQLITE_MAX_VARIABLES / BINDINGS_PER_ROW;
+    let mut affected = 0;
+
+    for chunk in rows.chunks(chunk_size) {
+        let placeholders = storage::generate_placeholder_groups(chunk.len(), BINDINGS_PER_ROW);
+        let sql = format!(
+            "
+INSERT OR IGNORE INTO file_tag (file_id, tag_id, value_id)
+VALUES {}",
+            placeholders
+        );
+
+        let mut params: Vec<Box<dyn rusqlite::ToSql>> = Vec::with_capacity(chunk.len() * BINDINGS_PER_ROW);
+        for &(file_id, tag_id, value_id) in chunk {
+            // A value ID of 0 in the DB actually means no value...
+            let value_id = value_id.unwrap_or(ValueId(0));
+
+            params.push(Box::new(file_id));
+            params.push(Box::new(tag_id));
+            params.push(Box::new(value_id));
+        }
+
+        // `sql` varies with `chunk.len()`, so caching it would just fill the statement cache with
+        // entries that are never reused - see `prepare_maybe_cached`.
+        affected += tx.execute_params_maybe_cached(&sql, params, false)?;
+    }
+
+    Ok(affected)
+}
+
+/// Delete a single (file, tag, value) association, as opposed to every row for a given tag or
+/// value. Used when an action needs to affect one matched file without disturbing the rest of
+/// the tag's usage.
+pub fn delete_file_tag(
+    tx: &mut Transaction,
+    file_id: FileId,
+    tag_id: TagId,
+    value_id: Option<ValueId>,
+) -> Result<usize> {
+    let sql = "
+DELETE FROM file_tag
+WHERE file_id = ?1 AND tag_id = ?2 AND value_id = ?3";
+
+    // A value ID of 0 in the DB actually means no value...
+    let value_id = value_id.unwrap_or(ValueId(0));
+
+    let params = rusqlite::params![file_id, tag_id, value_id];
+    tx.execute_params(sql, params)
+}
+
+pub fn delete_file_tags_by_file_id(tx: &mut Transaction, file_id: FileId) -> Result<usize> {
+    let sql = "
+DELETE FROM file_tag
+WHERE file_id = ?";
+
+    let params = rusqlite::params![file_id];
+    tx.execute_params(sql, params)
+}
+
 pub fn delete_file_tags_by_tag_id(tx: &mut Transaction, tag_id: &TagId) -> Result<usize> {
     let sql = "
 DELETE FROM file_tag
@@ -96,3 +176,36 @@ WHERE tag_id = ?1";
     let params = rusqlite::params![src_tag_id, dest_tag_id];
     tx.execute_params(sql, params)
 }
+
+/// Like `copy_file_tags`, but for merging one tag into another that may already share some of the
+/// same (file, value) associations: `INSERT OR IGNORE` silently skips rows that would otherwise
+/// duplicate an existing one.
+pub fn merge_file_tags(tx: &mut Transaction, src_tag_id: &TagId, dest_tag_id: &TagId) -> Result<usize> {
+    let sql = "
+INSERT OR IGNORE INTO file_tag (file_id, tag_id, value_id)
+SELECT file_id, ?2, value_id
+FROM file_tag
+WHERE tag_id = ?1";
+
+    let params = rusqlite::params![src_tag_id, dest_tag_id];
+    tx.execute_params(sql, params)
+}
+
+/// Value-based counterpart to `merge_file_tags`, used by `run_merge_value`.
+pub fn merge_file_tags_by_value(
+    tx: &mut Transaction,
+    src_value_id: &ValueId,
+    dest_value_id: &ValueId,
+) -> Result<usize> {
+    src_value_id.assert_non_zero("Bug: merging file tags from a value ID of 0 is meaningless.");
+    dest_value_id.assert_non_zero("Bug: merging file tags into a value ID of 0 is meaningless.");
+
+    let sql = "
+INSERT OR IGNORE INTO file_tag (file_id, tag_id, value_id)
+SELECT file_id, tag_id, ?2
+FROM file_tag
+WHERE value_id = ?1";
+
+    let params = rusqlite::params![src_value_id, dest_value_id];
+    tx.execute_params(sql, params)
+}
@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+
+use crate::entities::{FileId, FileTag, Tag, TagId, Value, ValueId};
+use crate::errors::*;
+use crate::storage::{FileTagStore, TagStore, ValueStore};
+
+/// An in-memory, `HashMap`-backed stand-in for a rusqlite `Transaction`, implementing the same
+/// `TagStore`/`ValueStore`/`FileTagStore` traits. This lets command logic built on top of those
+/// traits (cascade-delete, implication resolution, ...) be unit-tested quickly and
+/// deterministically, without creating a real sqlite database.
+#[derive(Default)]
+pub struct MemCatalog {
+    tags: HashMap<TagId, String>,
+    next_tag_id: u32,
+    values: HashMap<ValueId, String>,
+    next_value_id: u32,
+    file_tags: Vec<FileTag>,
+}
+
+impl MemCatalog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl TagStore for MemCatalog {
+    fn tags_by_names(&mut self, names: &[&str]) -> Result<Vec<Tag>> {
+        Ok(self
+            .tags
+            .iter()
+            .filter(|(_, name)| names.contains(&name.as_str()))
+            .map(|(&id, name)| Tag {
+                id,
+                name: name.clone(),
+            })
+            .collect())
+    }
+
+    fn tag_by_name(&mut self, name: &str) -> Result<Option<Tag>> {
+        if name.is_empty() {
+            return Ok(Some(Tag {
+                id: TagId(0),
+                name: String::new(),
+            }));
+        }
+
+        Ok(self.tags_by_names(&[name])?.into_iter().next())
+    }
+
+    fn tag_by_id(&mut self, tag_id: &TagId) -> Result<Option<Tag>> {
+        Ok(self.tags.get(tag_id).map(|name| Tag {
+            id: *tag_id,
+            name: name.clone(),
+        }))
+    }
+
+    fn insert_tag(&mut self, name: &str) -> Result<Tag> {
+        self.next_tag_id += 1;
+        let id = TagId(self.next_tag_id);
+        self.tags.insert(id, name.to_owned());
+        Ok(Tag {
+            id,
+            name: name.to_owned(),
+        })
+    }
+
+    fn rename_tag(&mut self, tag_id: &TagId, name: &str) -> Result<()> {
+        match self.tags.get_mut(tag_id) {
+            Some(existing) => {
+                *existing = name.to_owned();
+                Ok(())
+            }
+            None => Err("Expected exactly one row to be affected".into()),
+        }
+    }
+
+    fn delete_tag(&mut self, tag_id: &TagId) -> Result<()> {
+        match self.tags.remove(tag_id) {
+            Some(_) => Ok(()),
+            None => Err("Expected exactly one row to be affected".into()),
+        }
+    }
+}
+
+impl ValueStore for MemCatalog {
+    fn values(&mut self) -> Result<Vec<Value>> {
+        let mut values: Vec<_> = self
+            .values
+            .iter()
+            .map(|(&id, name)| Value {
+                id,
+                name: name.clone(),
+            })
+            .collect();
+        values.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(values)
+    }
+
+    fn values_by_names(&mut self, names: &[&str]) -> Result<Vec<Value>> {
+        Ok(self
+            .values
+            .iter()
+            .filter(|(_, name)| names.contains(&name.as_str()))
+            .map(|(&id, name)| Value {
+                id,
+                name: name.clone(),
+            })
+            .collect())
+    }
+
+    fn value_by_name(&mut self, name: &str) -> Result<Option<Value>> {
+        if name.is_empty() {
+            return Ok(Some(Value {
+                id: ValueId(0),
+                name: String::new(),
+            }));
+        }
+
+        Ok(self.values_by_names(&[name])?.into_iter().next())
+    }
+
+    fn insert_value(&mut self, name: &str) -> Result<Value> {
+        self.next_value_id += 1;
+        let id = ValueId(self.next_value_id);
+        self.values.insert(id, name.to_owned());
+        Ok(Value {
+            id,
+            name: name.to_owned(),
+        })
+    }
+
+    fn rename_value(&mut self, value_id: &ValueId, name: &str) -> Result<()> {
+        value_id.assert_non_zero("Bug: renaming a value with ID 0 is meaningless.");
+
+        match self.values.get_mut(value_id) {
+            Some(existing) => {
+                *existing = name.to_owned();
+                Ok(())
+            }
+            None => Err("Expected exactly one row to be affected".into()),
+        }
+    }
+
+    fn delete_value(&mut self, value_id: &ValueId) -> Result<()> {
+        value_id.assert_non_zero("Bug: deleting a value with ID 0 is meaningless.");
+
+        match self.values.remove(value_id) {
+            Some(_) => Ok(()),
+            None => Err("Expected exactly one row to be affected".into()),
+        }
+    }
+}
+
+impl FileTagStore for MemCatalog {
+    fn file_tags_by_tag_id(&mut self, tag_id: &TagId) -> Result<Vec<FileTag>> {
+        Ok(self
+            .file_tags
+            .iter()
+            .filter(|ft| ft.tag_id == *tag_id)
+            .cloned()
+            .collect())
+    }
+
+    fn file_tags_by_value_id(&mut self, value_id: &ValueId) -> Result<Vec<FileTag>> {
+        value_id.assert_non_zero("Bug: searching file tags with a value ID of 0 is meaningless.");
+
+        Ok(self
+            .file_tags
+            .iter()
+            .filter(|ft| ft.value_id == Some(*value_id))
+            .cloned()
+            .collect())
+    }
+
+    fn add_file_tag(
+        &mut self,
+        file_id: FileId,
+        tag_id: TagId,
+        value_id: Option<ValueId>,
+    ) -> Result<usize> {
+        let already_present = self
+            .file_tags
+            .iter()
+            .any(|ft| ft.file_id == file_id && ft.tag_id == tag_id && ft.value_id == value_id);
+        if already_present {
+            return Ok(0);
+        }
+
+        self.file_tags.push(FileTag {
+            file_id,
+            tag_id,
+            value_id,
+            explicit: true,
+            implicit: false,
+        });
+        Ok(1)
+    }
+
+    fn delete_file_tags_by_tag_id(&mut self, tag_id: &TagId) -> Result<usize> {
+        let before = self.file_tags.len();
+        self.file_tags.retain(|ft| ft.tag_id != *tag_id);
+        Ok(before - self.file_tags.len())
+    }
+
+    fn delete_file_tags_by_value_id(&mut self, value_id: &ValueId) -> Result<usize> {
+        value_id.assert_non_zero("Bug: deleting file tags with a value ID of 0 is meaningless.");
+
+        let before = self.file_tags.len();
+        self.file_tags.retain(|ft| ft.value_id != Some(*value_id));
+        Ok(before - self.file_tags.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delete_tag_cascades_to_its_file_tags() {
+        let mut store = MemCatalog::new();
+        let tag = store.insert_tag("music").unwrap();
+        store.add_file_tag(FileId(1), tag.id, None).unwrap();
+        store.add_file_tag(FileId(2), tag.id, None).unwrap();
+
+        store.delete_file_tags_by_tag_id(&tag.id).unwrap();
+        store.delete_tag(&tag.id).unwrap();
+
+        assert!(store.tag_by_name("music").unwrap().is_none());
+        assert!(store.file_tags_by_tag_id(&tag.id).unwrap().is_empty());
+    }
+
+    #[test]
+    fn delete_tag_fails_when_not_present() {
+        let mut store = MemCatalog::new();
+        assert!(store.delete_tag(&TagId(42)).is_err());
+    }
+
+    #[test]
+    fn add_file_tag_is_idempotent() {
+        let mut store = MemCatalog::new();
+        let tag = store.insert_tag("music").unwrap();
+
+        assert_eq!(store.add_file_tag(FileId(1), tag.id, None).unwrap(), 1);
+        assert_eq!(store.add_file_tag(FileId(1), tag.id, None).unwrap(), 0);
+        assert_eq!(store.file_tags_by_tag_id(&tag.id).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,159 @@
+use crate::errors::*;
+use crate::storage::{Row, Transaction};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RuleId(pub u32);
+
+/// How a rule decides whether it applies to a given path.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Matcher {
+    Glob(String),
+    Regex(String),
+}
+
+/// One step taken against a matched path, modelled on a mail filter's add/rm/run/del operations.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    Add {
+        tag_name: String,
+        value_name: Option<String>,
+    },
+    Rm {
+        tag_name: String,
+        value_name: Option<String>,
+    },
+    Run {
+        command: String,
+    },
+    Del,
+}
+
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub id: RuleId,
+    pub matcher: Matcher,
+    pub actions: Vec<Action>,
+}
+
+pub fn rules(tx: &mut Transaction) -> Result<Vec<Rule>> {
+    let sql = "
+SELECT id, pattern, pattern_kind
+FROM rule
+ORDER BY id";
+
+    let mut rules = tx.query_vec(sql, parse_rule_header)?;
+
+    for rule in &mut rules {
+        rule.actions = actions_for_rule(tx, &rule.id)?;
+    }
+
+    Ok(rules)
+}
+
+fn parse_rule_header(row: Row) -> Result<Rule> {
+    let pattern: String = row.get(1)?;
+    let kind: String = row.get(2)?;
+
+    let matcher = match kind.as_str() {
+        "glob" => Matcher::Glob(pattern),
+        "regex" => Matcher::Regex(pattern),
+        other => return Err(format!("unrecognized rule matcher kind '{}'", other).into()),
+    };
+
+    Ok(Rule {
+        id: RuleId(row.get(0)?),
+        matcher,
+        actions: Vec::new(),
+    })
+}
+
+fn actions_for_rule(tx: &mut Transaction, rule_id: &RuleId) -> Result<Vec<Action>> {
+    let sql = "
+SELECT kind, tag_name, value_name, command
+FROM rule_action
+WHERE rule_id = ?
+ORDER BY seq";
+
+    let params = rusqlite::params![rule_id.0];
+    tx.query_vec_params(sql, params, parse_action)
+}
+
+fn parse_action(row: Row) -> Result<Action> {
+    let kind: String = row.get(0)?;
+
+    Ok(match kind.as_str() {
+        "add" => Action::Add {
+            tag_name: row.get(1)?,
+            value_name: row.get(2)?,
+        },
+        "rm" => Action::Rm {
+            tag_name: row.get(1)?,
+            value_name: row.get(2)?,
+        },
+        "run" => Action::Run { command: row.get(3)? },
+        "del" => Action::Del,
+        other => return Err(format!("unrecognized rule action kind '{}'", other).into()),
+    })
+}
+
+/// Store a new rule together with its (ordered) actions.
+pub fn insert_rule(tx: &mut Transaction, matcher: &Matcher, actions: &[Action]) -> Result<Rule> {
+    let (pattern, kind) = match matcher {
+        Matcher::Glob(pattern) => (pattern.as_str(), "glob"),
+        Matcher::Regex(pattern) => (pattern.as_str(), "regex"),
+    };
+
+    let sql = "
+INSERT INTO rule (pattern, pattern_kind)
+VALUES (?, ?)";
+    tx.execute_params(sql, rusqlite::params![pattern, kind])?;
+    let rule_id = RuleId(tx.last_inserted_row_id());
+
+    for (seq, action) in actions.iter().enumerate() {
+        insert_action(tx, &rule_id, seq as u32, action)?;
+    }
+
+    Ok(Rule {
+        id: rule_id,
+        matcher: matcher.clone(),
+        actions: actions.to_vec(),
+    })
+}
+
+fn insert_action(tx: &mut Transaction, rule_id: &RuleId, seq: u32, action: &Action) -> Result<()> {
+    let sql = "
+INSERT INTO rule_action (rule_id, seq, kind, tag_name, value_name, command)
+VALUES (?, ?, ?, ?, ?, ?)";
+
+    let (kind, tag_name, value_name, command): (&str, Option<&str>, Option<&str>, Option<&str>) =
+        match action {
+            Action::Add { tag_name, value_name } => {
+                ("add", Some(tag_name.as_str()), value_name.as_deref(), None)
+            }
+            Action::Rm { tag_name, value_name } => {
+                ("rm", Some(tag_name.as_str()), value_name.as_deref(), None)
+            }
+            Action::Run { command } => ("run", None, None, Some(command.as_str())),
+            Action::Del => ("del", None, None, None),
+        };
+
+    let params = rusqlite::params![rule_id.0, seq, kind, tag_name, value_name, command];
+    tx.execute_params(sql, params)?;
+    Ok(())
+}
+
+pub fn delete_rule(tx: &mut Transaction, rule_id: &RuleId) -> Result<()> {
+    let sql = "
+DELETE FROM rule_action
+WHERE rule_id = ?";
+    tx.execute_params(sql, rusqlite::params![rule_id.0])?;
+
+    let sql = "
+DELETE FROM rule
+WHERE id = ?";
+    match tx.execute_params(sql, rusqlite::params![rule_id.0]) {
+        Ok(1) => Ok(()),
+        Ok(_) => Err("Expected exactly one row to be affected".into()),
+        Err(e) => Err(e),
+    }
+}
@@ -6,29 +6,32 @@ pub fn tag_count(tx: &mut Transaction) -> Result<u64> {
     tx.count_from_table("tag")
 }
 
-pub fn tags_by_names(tx: &mut Transaction, names: &[&str]) -> Result<Vec<Tag>> {
-    if names.is_empty() {
-        return Ok(vec![]);
-    }
+pub fn tags(tx: &mut Transaction) -> Result<Vec<Tag>> {
+    let sql = "
+SELECT id, name
+FROM tag
+ORDER BY name";
 
-    let (placeholders, params) = storage::generate_placeholders(names)?;
+    tx.query_vec(sql, parse_tag)
+}
 
-    let sql = format!(
+pub fn tags_by_names(tx: &mut Transaction, names: &[&str]) -> Result<Vec<Tag>> {
+    tx.query_vec_chunked(
         "
 SELECT id, name
 FROM tag
-WHERE name IN ({})",
-        &placeholders
-    );
-
-    fn parse_tag(row: Row) -> Result<Tag> {
-        Ok(Tag {
-            id: row.get(0)?,
-            name: row.get(1)?,
-        })
-    }
+WHERE name IN",
+        names,
+        storage::MAX_CHUNKED_QUERY_PARAMS,
+        parse_tag,
+    )
+}
 
-    tx.query_vec_params(&sql, &params, parse_tag)
+fn parse_tag(row: Row) -> Result<Tag> {
+    Ok(Tag {
+        id: row.get(0)?,
+        name: row.get(1)?,
+    })
 }
 
 pub fn tag_by_name(tx: &mut Transaction, name: &str) -> Result<Option<Tag>> {
@@ -16,21 +16,15 @@ ORDER BY name";
 }
 
 pub fn values_by_names(tx: &mut Transaction, names: &[&str]) -> Result<Vec<Value>> {
-    if names.is_empty() {
-        return Ok(vec![]);
-    }
-
-    let (placeholders, params) = storage::generate_placeholders(names)?;
-
-    let sql = format!(
+    tx.query_vec_chunked(
         "
 SELECT id, name
 FROM value
-WHERE name IN ({})",
-        &placeholders
-    );
-
-    tx.query_vec_params(&sql, &params, parse_value)
+WHERE name IN",
+        names,
+        storage::MAX_CHUNKED_QUERY_PARAMS,
+        parse_value,
+    )
 }
 
 pub fn value_by_name(tx: &mut Transaction, name: &str) -> Result<Option<Value>> {